@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::str::from_utf8;
+
+use BencodeObject;
+use err::BencodeError;
+use tokenizer::{Tokenizer, Token};
+
+
+// Borrowed counterpart of BencodeObject: byte strings and dict keys point back into the
+// parsed input instead of being copied, so e.g. a torrent's multi-megabyte `pieces` costs
+// nothing extra.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BencodeRef<'a> {
+    Integer(i64),
+    Bytes(&'a [u8]),
+    List(Vec<BencodeRef<'a>>),
+    Dict(BTreeMap<&'a str, BencodeRef<'a>>),
+}
+
+impl<'a> BencodeRef<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<BencodeRef<'a>, BencodeError> {
+        let mut tokenizer = Tokenizer::new(bytes);
+        let value = match tokenizer.next() {
+            Some(token) => _build(token?, &mut tokenizer)?,
+            None => return Err(BencodeError::UnexpectedEndOfInput),
+        };
+        if tokenizer.position() == bytes.len() {
+            Ok(value)
+        } else {
+            Err(BencodeError::UnexpectedCharacter(tokenizer.position()))
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BencodeRef<'a>> {
+        match *self {
+            BencodeRef::Dict(ref dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    // Copies everything into the owned representation.
+    pub fn to_owned(&self) -> BencodeObject {
+        match *self {
+            BencodeRef::Integer(i) => BencodeObject::Integer(i),
+            BencodeRef::Bytes(bytes) => BencodeObject::Bytes(bytes.to_vec()),
+            BencodeRef::List(ref list) => BencodeObject::List(list.iter().map(|o| o.to_owned()).collect()),
+            BencodeRef::Dict(ref dict) => BencodeObject::Dict(dict.iter()
+                .map(|(key, value)| (key.to_string(), value.to_owned()))
+                .collect()),
+        }
+    }
+}
+
+// Builds the value that starts with `token`, pulling the rest of it from `tokenizer`. The
+// tokenizer has already checked nesting and that dict keys are byte strings.
+fn _build<'a>(token: Token<'a>, tokenizer: &mut Tokenizer<'a>) -> Result<BencodeRef<'a>, BencodeError> {
+    match token {
+        Token::Integer(i) => Ok(BencodeRef::Integer(i)),
+        Token::Bytes(bytes) => Ok(BencodeRef::Bytes(bytes)),
+        Token::ListStart => {
+            let mut list = Vec::new();
+            loop {
+                match _next(tokenizer)? {
+                    Token::ListEnd => return Ok(BencodeRef::List(list)),
+                    token => list.push(_build(token, tokenizer)?),
+                }
+            }
+        },
+        Token::DictStart => {
+            let mut dict = BTreeMap::new();
+            let mut last_key: Option<&[u8]> = None;
+            loop {
+                let key_start = Tokenizer::position(tokenizer);
+                match _next(tokenizer)? {
+                    Token::DictEnd => return Ok(BencodeRef::Dict(dict)),
+                    Token::Bytes(key) => {
+                        if last_key.is_some_and(|last| key <= last) {
+                            return Err(BencodeError::UnorderedDictKeys(key_start));
+                        }
+                        last_key = Some(key);
+                        let key = from_utf8(key)?;
+                        let value = _next(tokenizer)?;
+                        dict.insert(key, _build(value, tokenizer)?);
+                    },
+                    _ => return Err(BencodeError::DictionaryKeyNotString),
+                }
+            }
+        },
+        Token::ListEnd | Token::DictEnd => Err(BencodeError::UnexpectedCharacter(Tokenizer::position(tokenizer) - 1)),
+    }
+}
+
+fn _next<'a>(tokenizer: &mut Tokenizer<'a>) -> Result<Token<'a>, BencodeError> {
+    match tokenizer.next() {
+        Some(token) => token,
+        None => Err(BencodeError::UnexpectedEndOfInput),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use BencodeObject;
+    use bencode_ref::BencodeRef;
+    use err::BencodeError;
+
+    #[test]
+    fn test_parse_ref_borrows_input() {
+        let bytes = b"d4:infod4:name3:foo6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe4:listli1ei-2eee";
+        let parsed = BencodeObject::parse_ref(bytes).unwrap();
+        let pieces = match parsed.get("info").and_then(|info| info.get("pieces")) {
+            Some(&BencodeRef::Bytes(pieces)) => pieces,
+            other => panic!("expected pieces, got {:?}", other),
+        };
+        assert_eq!(pieces.len(), 40);
+        let offset = pieces.as_ptr() as usize - bytes.as_ptr() as usize;
+        assert_eq!(&bytes[offset .. offset + 40], pieces);
+
+        assert_eq!(parsed.to_owned(), BencodeObject::parse(&bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn test_parse_ref_errors() {
+        match BencodeRef::parse(b"i1ei2e") {
+            Err(BencodeError::UnexpectedCharacter(3)) => (),
+            other => panic!("expected UnexpectedCharacter, got {:?}", other),
+        }
+        match BencodeRef::parse(b"d1:a") {
+            Err(BencodeError::UnexpectedEndOfInput) => (),
+            other => panic!("expected UnexpectedEndOfInput, got {:?}", other),
+        }
+        match BencodeRef::parse(b"d2:\xff\xffi1ee") {
+            Err(BencodeError::Utf8(_)) => (),
+            other => panic!("expected Utf8, got {:?}", other),
+        }
+        assert!(BencodeRef::parse(b"").is_err());
+    }
+
+    #[test]
+    fn test_parse_ref_unordered_dict_keys() {
+        match BencodeRef::parse(b"d1:b1:x1:a1:ye") {
+            Err(BencodeError::UnorderedDictKeys(7)) => (),
+            other => panic!("expected UnorderedDictKeys(7), got {:?}", other),
+        }
+        // A duplicate key is out of order too, rather than overwriting the first value.
+        match BencodeRef::parse(b"li1ed1:a1:x1:a1:yee") {
+            Err(BencodeError::UnorderedDictKeys(11)) => (),
+            other => panic!("expected UnorderedDictKeys(11), got {:?}", other),
+        }
+        assert_eq!(format!("{:?}", BencodeObject::parse("li1ed1:a1:x1:a1:yee")), "Err(UnorderedDictKeys(11))");
+        let parsed = BencodeRef::parse(b"d1:a1:x1:b1:ye").unwrap();
+        assert_eq!(parsed.to_owned(), BencodeObject::parse("d1:a1:x1:b1:ye").unwrap());
+    }
+}
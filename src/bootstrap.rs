@@ -0,0 +1,108 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+
+pub const DEFAULT_BOOTSTRAP_HOSTS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Last known addresses of the default bootstrap hosts, for when DNS is slow or broken.
+const FALLBACK_NODES: [&str; 3] = [
+    "67.215.246.10:6881",
+    "87.98.162.88:6881",
+    "82.221.103.244:6881",
+];
+
+
+// Resolves "host:port" strings with the system resolver. Hosts that fail to resolve are
+// skipped; if nothing resolves within RESOLVE_TIMEOUT the hard-coded fallback list is used.
+// An empty list means DEFAULT_BOOTSTRAP_HOSTS.
+pub fn resolve_bootstrap_nodes(hosts: &[&str]) -> Vec<SocketAddr> {
+    resolve_with(hosts, RESOLVE_TIMEOUT, |host| host.to_socket_addrs().map(|addrs| addrs.collect()))
+}
+
+// The lookup runs on its own thread, since std gives no way to bound a blocking DNS query.
+// A timed-out thread is left to finish on its own.
+fn resolve_with<F>(hosts: &[&str], timeout: Duration, resolve: F) -> Vec<SocketAddr>
+    where F: Fn(&str) -> Result<Vec<SocketAddr>, io::Error> + Send + 'static {
+    let hosts = if hosts.is_empty() { &DEFAULT_BOOTSTRAP_HOSTS[..] } else { hosts };
+    let hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let addrs: Vec<SocketAddr> = hosts.iter()
+            .filter_map(|host| resolve(host).ok())
+            .flatten()
+            .collect();
+        let _ = sender.send(addrs);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(ref addrs) if !addrs.is_empty() => addrs.clone(),
+        _ => fallback_nodes(),
+    }
+}
+
+fn fallback_nodes() -> Vec<SocketAddr> {
+    FALLBACK_NODES.iter().map(|addr| addr.parse().unwrap()).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use bootstrap::{DEFAULT_BOOTSTRAP_HOSTS, resolve_bootstrap_nodes, resolve_with, fallback_nodes};
+
+    // Stands in for DNS: knows two hosts, each with a fixed address, and keeps the port.
+    fn mock_resolve(host: &str) -> Result<Vec<SocketAddr>, io::Error> {
+        let mut parts = host.rsplitn(2, ':');
+        let port = parts.next().unwrap();
+        let ip = match parts.next() {
+            Some("router.example.com") => "10.0.0.1",
+            Some("dht.example.org") => "10.0.0.2",
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound, "unknown host")),
+        };
+        Ok(vec![format!("{}:{}", ip, port).parse().unwrap()])
+    }
+
+    #[test]
+    fn test_resolve_with_mock() {
+        let addrs = resolve_with(&["router.example.com:6881", "missing.example.net:1", "dht.example.org:6882"],
+                                 Duration::from_secs(5), mock_resolve);
+        assert_eq!(addrs, vec!["10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_resolve_defaults() {
+        let echo = |host: &str| {
+            let index = DEFAULT_BOOTSTRAP_HOSTS.iter().position(|default| *default == host).unwrap();
+            Ok(vec![format!("10.0.1.{}:6881", index).parse().unwrap()])
+        };
+        assert_eq!(resolve_with(&[], Duration::from_secs(5), echo).len(), DEFAULT_BOOTSTRAP_HOSTS.len());
+    }
+
+    #[test]
+    fn test_resolve_falls_back() {
+        assert_eq!(resolve_with(&["missing.example.net:1"], Duration::from_secs(5), mock_resolve), fallback_nodes());
+
+        let slow = |host: &str| {
+            thread::sleep(Duration::from_millis(200));
+            mock_resolve(host)
+        };
+        assert_eq!(resolve_with(&["router.example.com:6881"], Duration::from_millis(10), slow), fallback_nodes());
+    }
+
+    #[test]
+    fn test_resolve_ip_literals() {
+        assert_eq!(resolve_bootstrap_nodes(&["127.0.0.1:6881"]), vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+}
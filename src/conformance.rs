@@ -0,0 +1,139 @@
+// Data-driven known-answer tests. Each file in tests/conformance/ holds one vector per line:
+//
+//     <operation> <input> => <expected>
+//
+// Inputs and expected outputs are written literally, or as `hex:<digits>` for arbitrary bytes.
+// Blank lines and lines starting with `#` are ignored. See tests/conformance/README for the
+// supported operations.
+
+use std::fs;
+use std::path::Path;
+use std::str::from_utf8;
+
+use BencodeObject;
+
+
+pub struct Vector {
+    pub location: String,
+    pub operation: String,
+    pub input: Vec<u8>,
+    pub expected: String,
+}
+
+pub fn load_vectors(path: &Path) -> Result<Vec<Vector>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut vectors = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{}:{}", path.display(), n + 1);
+        let (lhs, expected) = match line.find(" => ") {
+            Some(pos) => (&line[..pos], line[pos + 4..].trim()),
+            None => return Err(format!("{}: missing ` => `", location)),
+        };
+        let (operation, input) = match lhs.find(' ') {
+            Some(pos) => (&lhs[..pos], &lhs[pos + 1..]),
+            None => (lhs, ""),
+        };
+        let input = decode_field(input).map_err(|err| format!("{}: {}", location, err))?;
+        vectors.push(Vector {
+            location,
+            operation: operation.to_string(),
+            input,
+            expected: expected.to_string(),
+        });
+    }
+    Ok(vectors)
+}
+
+pub fn load_dir(dir: &Path) -> Result<Vec<Vector>, String> {
+    let mut paths: Vec<_> = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+
+    let mut vectors = Vec::new();
+    for path in paths {
+        vectors.extend(load_vectors(&path)?);
+    }
+    Ok(vectors)
+}
+
+// Runs one vector against the public API, describing the mismatch on failure.
+pub fn run_vector(vector: &Vector) -> Result<(), String> {
+    match &vector.operation[..] {
+        "parse" => {
+            let actual = match BencodeObject::parse(&vector.input[..]) {
+                Ok(_) => "ok".to_string(),
+                Err(err) => format!("error {:?}", err),
+            };
+            check_outcome(&actual, &vector.expected)
+        },
+        "roundtrip" => {
+            let expected = decode_field(&vector.expected)?;
+            match BencodeObject::parse(&vector.input[..]) {
+                Ok(obj) => {
+                    let actual = obj.into_bytes();
+                    if actual == expected {
+                        Ok(())
+                    } else {
+                        Err(format!("encoded to {:?}", String::from_utf8_lossy(&actual)))
+                    }
+                },
+                Err(err) => Err(format!("failed to parse: {:?}", err)),
+            }
+        },
+        operation => Err(format!("unknown operation `{}`", operation)),
+    }
+}
+
+// `expected` is either `ok`, `error`, or `error <prefix of the error's Debug output>`.
+fn check_outcome(actual: &str, expected: &str) -> Result<(), String> {
+    let matches = if expected == "error" {
+        actual.starts_with("error")
+    } else {
+        actual.starts_with(expected)
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(format!("got `{}`", actual))
+    }
+}
+
+fn decode_field(field: &str) -> Result<Vec<u8>, String> {
+    if !field.starts_with("hex:") {
+        return Ok(field.as_bytes().to_vec());
+    }
+    let digits = &field.as_bytes()[4..];
+    if digits.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in `{}`", field));
+    }
+    digits.chunks(2)
+        .map(|pair| from_utf8(pair).ok()
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| format!("invalid hex in `{}`", field)))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use conformance::{load_dir, run_vector};
+
+    #[test]
+    fn test_conformance_vectors() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance");
+        let vectors = load_dir(&dir).unwrap();
+        assert!(!vectors.is_empty());
+
+        let failures: Vec<String> = vectors.iter()
+            .filter_map(|vector| run_vector(vector).err().map(|err| format!("{}: {}", vector.location, err)))
+            .collect();
+        assert!(failures.is_empty(), "conformance failures:\n{}", failures.join("\n"));
+    }
+}
@@ -1,7 +1,9 @@
 use std::str::Utf8Error;
 use std::num::ParseIntError;
+use std::path::PathBuf;
 use std::fmt;
 use std::error;
+use std::io;
 
 #[derive(Debug)]
 pub enum BencodeError {
@@ -10,6 +12,19 @@ pub enum BencodeError {
     DictionaryKeyNotString,
     UnexpectedCharacter(usize),
     UnexpectedEndOfInput,
+    LengthOverflow(usize),
+    File(FileError),
+    InvalidPatchPath(String),
+    CollectionTooLarge { len: usize, limit: usize },
+    // A byte order mark or whitespace of the given length before the value.
+    LeadingGarbage(usize),
+    Io(io::Error),
+    // The input needed more bytes than the given limit.
+    InputTooLarge(usize),
+    // A dict key at the given position that doesn't sort strictly after the one before it.
+    UnorderedDictKeys(usize),
+    // A list or dict at the given position nested deeper than the parser allows.
+    TooDeep(usize),
 }
 
 impl fmt::Display for BencodeError {
@@ -20,10 +35,62 @@ impl fmt::Display for BencodeError {
             BencodeError::DictionaryKeyNotString => write!(f, "Dictionary key was not a string"),
             BencodeError::UnexpectedCharacter(ref position) => write!(f, "Unexpected character: position {}", position),
             BencodeError::UnexpectedEndOfInput => write!(f, "Unexpected end of input"),
+            BencodeError::LengthOverflow(ref position) => write!(f, "Byte string length overflow: position {}", position),
+            BencodeError::File(ref err) => write!(f, "{}", err),
+            BencodeError::InvalidPatchPath(ref path) => write!(f, "Invalid patch path: {}", path),
+            BencodeError::CollectionTooLarge { len, limit } => write!(f, "Collection too large: {} elements, limit {}", len, limit),
+            BencodeError::LeadingGarbage(len) => write!(f, "Byte order mark or whitespace before value: {} bytes", len),
+            BencodeError::Io(ref err) => write!(f, "IO error: {}", err),
+            BencodeError::InputTooLarge(limit) => write!(f, "Input larger than limit of {} bytes", limit),
+            BencodeError::UnorderedDictKeys(position) => write!(f, "Dictionary key out of order at position {}", position),
+            BencodeError::TooDeep(position) => write!(f, "Nesting too deep at position {}", position),
         }
     }
 }
 
+// How many bytes on either side of an error position ErrorContext shows.
+const CONTEXT_WINDOW: usize = 16;
+
+// A readable window around an error position, for showing to users.
+#[derive(Debug, PartialEq)]
+pub struct ErrorContext {
+    pub offset: usize,
+    pub before: String,
+    pub after: String,
+}
+
+impl BencodeError {
+    pub fn position(&self) -> Option<usize> {
+        match *self {
+            BencodeError::UnexpectedCharacter(position) => Some(position),
+            BencodeError::LengthOverflow(position) => Some(position),
+            BencodeError::UnorderedDictKeys(position) => Some(position),
+            BencodeError::TooDeep(position) => Some(position),
+            // LeadingGarbage holds a length, not a position: the problem starts at byte 0.
+            _ => None,
+        }
+    }
+
+    // Lossy text just before and from the error's position in `input`. None if the error
+    // carries no position.
+    pub fn context(&self, input: &[u8]) -> Option<ErrorContext> {
+        let offset = self.position()?.min(input.len());
+        let start = offset.saturating_sub(CONTEXT_WINDOW);
+        let end = (offset + CONTEXT_WINDOW).min(input.len());
+        Some(ErrorContext {
+            offset,
+            before: String::from_utf8_lossy(&input[start..offset]).into_owned(),
+            after: String::from_utf8_lossy(&input[offset..end]).into_owned(),
+        })
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {} (near `{}` <here> `{}`)", self.offset, self.before, self.after)
+    }
+}
+
 impl error::Error for BencodeError {
     fn description(&self) -> &str {
         match *self {
@@ -32,6 +99,15 @@ impl error::Error for BencodeError {
             BencodeError::DictionaryKeyNotString => "Dictionary key was not a string",
             BencodeError::UnexpectedCharacter(_) => "Unexpected character",
             BencodeError::UnexpectedEndOfInput => "Unexpected end of input",
+            BencodeError::LengthOverflow(_) => "Byte string length overflow",
+            BencodeError::File(_) => "Failed to read file",
+            BencodeError::InvalidPatchPath(_) => "Invalid patch path",
+            BencodeError::CollectionTooLarge { .. } => "Collection too large",
+            BencodeError::LeadingGarbage(_) => "Byte order mark or whitespace before value",
+            BencodeError::Io(_) => "IO error",
+            BencodeError::InputTooLarge(_) => "Input larger than limit",
+            BencodeError::UnorderedDictKeys(_) => "Dictionary key out of order",
+            BencodeError::TooDeep(_) => "Nesting too deep",
         }
     }
 }
@@ -48,3 +124,87 @@ impl From<ParseIntError> for BencodeError {
     }
 }
 
+
+impl From<FileError> for BencodeError {
+    fn from(err: FileError) -> BencodeError {
+        BencodeError::File(err)
+    }
+}
+
+
+#[derive(Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub err: io::Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to read '{}': {}", self.path.display(), self.err)
+    }
+}
+
+impl error::Error for FileError {
+    fn description(&self) -> &str {
+        "Failed to read file"
+    }
+}
+
+
+#[derive(Debug)]
+pub enum KrpcError {
+    Bencode(BencodeError),
+    MalformedMessage(&'static str),
+}
+
+impl fmt::Display for KrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KrpcError::Bencode(ref err) => write!(f, "Bencode error: {}", err),
+            KrpcError::MalformedMessage(ref reason) => write!(f, "Malformed message: {}", reason),
+        }
+    }
+}
+
+impl error::Error for KrpcError {
+    fn description(&self) -> &str {
+        match *self {
+            KrpcError::Bencode(_) => "Bencode error",
+            KrpcError::MalformedMessage(_) => "Malformed message",
+        }
+    }
+}
+
+impl From<BencodeError> for KrpcError {
+    fn from(err: BencodeError) -> KrpcError {
+        KrpcError::Bencode(err)
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum NodeIdError {
+    Length(usize),
+    Character(usize),
+    Urn,
+}
+
+impl fmt::Display for NodeIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NodeIdError::Length(ref len) => write!(f, "Invalid node id length: {}", len),
+            NodeIdError::Character(ref position) => write!(f, "Invalid character in node id: position {}", position),
+            NodeIdError::Urn => write!(f, "Not a urn:btih: component"),
+        }
+    }
+}
+
+impl error::Error for NodeIdError {
+    fn description(&self) -> &str {
+        match *self {
+            NodeIdError::Length(_) => "Invalid node id length",
+            NodeIdError::Character(_) => "Invalid character in node id",
+            NodeIdError::Urn => "Not a urn:btih: component",
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// 20-byte hashing behind a small trait, so info-hashes, piece checks and the like can run on
+// whatever SHA-1 implementation the embedding application prefers. The built-in Sha1 is a
+// plain software implementation, available with the `sha1` feature (on by default).
+
+pub trait Hasher20 {
+    fn update(&mut self, bytes: &[u8]);
+    fn finish(self) -> [u8; 20];
+}
+
+pub fn hash20<H: Hasher20>(mut hasher: H, bytes: &[u8]) -> [u8; 20] {
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+
+#[cfg(feature = "sha1")]
+pub struct Sha1 {
+    state: [u32; 5],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+#[cfg(feature = "sha1")]
+impl Sha1 {
+    pub fn new() -> Sha1 {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn process_block(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = (chunk[0] as u32) << 24 | (chunk[1] as u32) << 16 | (chunk[2] as u32) << 8 | chunk[3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0 ..= 19 => ((b & c) | (!b & d), 0x5a827999),
+                20 ..= 39 => (b ^ c ^ d, 0x6ed9eba1),
+                40 ..= 59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        Sha1::new()
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl Hasher20 for Sha1 {
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = (64 - self.block_len).min(bytes.len());
+            self.block[self.block_len .. self.block_len + n].copy_from_slice(&bytes[..n]);
+            self.block_len += n;
+            bytes = &bytes[n..];
+            if self.block_len == 64 {
+                self.process_block();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 20] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        let padding_len = if self.block_len < 56 { 56 - self.block_len } else { 120 - self.block_len };
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        for i in 0..8 {
+            padding[padding_len + i] = (bit_len >> (56 - 8 * i)) as u8;
+        }
+        let total_len = self.total_len;
+        self.update(&padding[..padding_len + 8]);
+        self.total_len = total_len;
+
+        let mut digest = [0; 20];
+        for (i, word) in self.state.iter().enumerate() {
+            digest[i * 4] = (word >> 24) as u8;
+            digest[i * 4 + 1] = (word >> 16) as u8;
+            digest[i * 4 + 2] = (word >> 8) as u8;
+            digest[i * 4 + 3] = *word as u8;
+        }
+        digest
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hash::{Hasher20, hash20};
+
+    fn hex(digest: &[u8; 20]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Stand-in for a user-supplied hasher: XORs input into a 20-byte state.
+    struct XorHasher([u8; 20], usize);
+
+    impl Hasher20 for XorHasher {
+        fn update(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.0[self.1 % 20] ^= *byte;
+                self.1 += 1;
+            }
+        }
+
+        fn finish(self) -> [u8; 20] {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_pluggable_hasher() {
+        let digest = hash20(XorHasher([0; 20], 0), b"abcdefghijklmnopqrstuv");
+        assert_eq!(&digest[..3], &[b'a' ^ b'u', b'b' ^ b'v', b'c']);
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn test_sha1_known_vectors() {
+        use hash::Sha1;
+
+        assert_eq!(hex(&hash20(Sha1::new(), b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&hash20(Sha1::new(), b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&hash20(Sha1::new(), b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+
+        let mut hasher = Sha1::new();
+        for _ in 0..1000 {
+            hasher.update(&[b'a'; 1000]);
+        }
+        assert_eq!(hex(&hasher.finish()), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+}
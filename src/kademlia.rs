@@ -1,76 +1,202 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use {BencodeObject, Bencodeable, temp_path};
+use err::NodeIdError;
 
-#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Hash, Debug)]
+
+// Stored big-endian, exactly as on the wire, so the derived ordering compares ids as 160-bit
+// unsigned integers.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct NodeId {
-    pub data: [u32; 5]
+    pub data: [u8; 20]
 }
 
 impl NodeId {
+    pub const MIN: NodeId = NodeId {data: [0; 20]};
+    pub const MAX: NodeId = NodeId {data: [0xff; 20]};
+    pub const ZERO: NodeId = NodeId::MIN;
+
+    // Takes 32-bit words least significant first, which keeps small test ids short to write.
     fn new(a: u32, b: u32, c: u32, d: u32, e: u32) -> NodeId {
-        NodeId {data: [a, b, c, d, e]}
+        let mut node_id = NodeId::ZERO;
+        for (chunk, word) in node_id.data.chunks_mut(4).zip(&[e, d, c, b, a]) {
+            chunk[0] = (word >> 24) as u8;
+            chunk[1] = (word >> 16) as u8;
+            chunk[2] = (word >> 8) as u8;
+            chunk[3] = *word as u8;
+        }
+        node_id
     }
 
-    fn midpoint(&self, other: NodeId) -> NodeId {
-        let mut self_div_2 = self.clone();
-        self_div_2.data[0] >>= 1;
-        for i in 1..5 {
-            if self_div_2.data[i] & 1 == 1 {
-                self_div_2.data[i-1] |= 0x80000000;
-            }
-            self_div_2.data[i] >>= 1;
+    pub fn from_bytes(bytes: &[u8; 20]) -> NodeId {
+        NodeId {data: *bytes}
+    }
+
+    pub fn to_bytes(self) -> [u8; 20] {
+        self.data
+    }
+
+    // Matches an id as it appears on the wire, e.g. a response's `id`, without decoding it.
+    // Node ids aren't secret, so this is a plain comparison; see bytes_eq_ct for tokens.
+    pub fn eq_bytes(&self, bytes: &[u8]) -> bool {
+        self.data[..] == *bytes
+    }
+
+    pub fn from_hex(s: &str) -> Result<NodeId, NodeIdError> {
+        if s.len() != 40 {
+            return Err(NodeIdError::Length(s.len()));
         }
+        let mut bytes = [0; 20];
+        for (i, c) in s.chars().enumerate() {
+            let digit = c.to_digit(16).ok_or(NodeIdError::Character(i))? as u8;
+            bytes[i / 2] |= if i % 2 == 0 { digit << 4 } else { digit };
+        }
+        Ok(NodeId::from_bytes(&bytes))
+    }
 
-        let mut other_div_2 = other.clone();
-        other_div_2.data[0] >>= 1;
-        for i in 1..5 {
-            if other_div_2.data[i] & 1 == 1 {
-                other_div_2.data[i-1] |= 0x80000000;
+    // RFC 4648 base32, as used by older magnet links. Case-insensitive, no padding.
+    pub fn from_base32(s: &str) -> Result<NodeId, NodeIdError> {
+        if s.len() != 32 {
+            return Err(NodeIdError::Length(s.len()));
+        }
+        let mut bytes = [0; 20];
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+        let mut n = 0;
+        for (i, c) in s.chars().enumerate() {
+            let value = match c.to_ascii_uppercase() {
+                c @ 'A' ..= 'Z' => c as u32 - 'A' as u32,
+                c @ '2' ..= '7' => c as u32 - '2' as u32 + 26,
+                _ => return Err(NodeIdError::Character(i)),
+            };
+            buffer = buffer << 5 | value;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes[n] = (buffer >> bits) as u8;
+                n += 1;
             }
-            other_div_2.data[i] >>= 1;
         }
+        Ok(NodeId::from_bytes(&bytes))
+    }
 
-        let mut carry: u32 = self.data[0] & other.data[0] & 1;
-        let mut final_node_id = NodeId {data: [0, 0, 0, 0, 0]};
-        for i in 0..5 {
-            let datum1 = self_div_2.data[i] as u64;
-            let datum2 = other_div_2.data[i] as u64;
-            let mut val = datum1 + datum2 + carry as u64;
-            carry = (val > 0xffffffff) as u32;
-            final_node_id.data[i] = val as u32;
+    // Parses the info-hash out of a magnet link's `xt` value, e.g. `urn:btih:<hash>`
+    // (optionally still prefixed with `xt=`), in either 40-char hex or 32-char base32 form.
+    pub fn from_magnet_urn(s: &str) -> Result<NodeId, NodeIdError> {
+        let s = s.strip_prefix("xt=").unwrap_or(s);
+        let hash = s.strip_prefix("urn:btih:").ok_or(NodeIdError::Urn)?;
+        match hash.len() {
+            40 => NodeId::from_hex(hash),
+            32 => NodeId::from_base32(hash),
+            len => Err(NodeIdError::Length(len)),
         }
+    }
 
-        return final_node_id;
+    // (self + other) / 2, rounded down, without overflowing 160 bits.
+    fn midpoint(&self, other: NodeId) -> NodeId {
+        let mut sum = [0u16; 20];
+        let mut carry = 0;
+        for i in (0..20).rev() {
+            let val = self.data[i] as u16 + other.data[i] as u16 + carry;
+            sum[i] = val & 0xff;
+            carry = val >> 8;
+        }
+
+        let mut final_node_id = NodeId::ZERO;
+        for (byte, val) in final_node_id.data.iter_mut().zip(sum.iter()) {
+            *byte = (val >> 1) as u8 | (carry << 7) as u8;
+            carry = val & 1;
+        }
+        final_node_id
     }
 
     fn plus_one(&self) -> NodeId {
-        let mut new_node_id = self.clone();
+        let mut new_node_id = *self;
 
-        for i in 0..5 {
-            if new_node_id.data[i] == 0xffffffff {
-                new_node_id.data[i] = 0;
+        for byte in new_node_id.data.iter_mut().rev() {
+            if *byte == 0xff {
+                *byte = 0;
             } else {
-                new_node_id.data[i] += 1;
+                *byte += 1;
                 break;
             }
         }
 
-        return new_node_id;
+        new_node_id
+    }
+
+    // The Kademlia XOR metric, compared as a 160-bit unsigned integer.
+    fn distance(&self, other: NodeId) -> NodeId {
+        self.xor(other)
     }
 
-    fn distance(&self, other: NodeId) -> u32 {
-        (self.data[0] ^ other.data[0]).count_ones() +
-        (self.data[1] ^ other.data[1]).count_ones() +
-        (self.data[2] ^ other.data[2]).count_ones() +
-        (self.data[3] ^ other.data[3]).count_ones() +
-        (self.data[4] ^ other.data[4]).count_ones()
+    // Whether we are strictly closer to `target` than `other` is.
+    fn is_closer_than(&self, other: NodeId, target: NodeId) -> bool {
+        self.distance(target) < other.distance(target)
+    }
+
+    // Number of differing bits. Not a routing metric, but a measure of id similarity.
+    fn hamming_distance(&self, other: &NodeId) -> u32 {
+        self.xor(*other).data.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    fn leading_zeros(self) -> u32 {
+        let mut zeros = 0;
+        for byte in self.data.iter() {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+
+    fn xor(self, other: NodeId) -> NodeId {
+        let mut result = NodeId::ZERO;
+        for i in 0..20 {
+            result.data[i] = self.data[i] ^ other.data[i];
+        }
+        result
+    }
+
+    // The sign is true when `a >= b`; the magnitude is the XOR metric between the two.
+    pub fn signed_distance(a: NodeId, b: NodeId) -> (bool, NodeId) {
+        (a >= b, a.xor(b))
+    }
+
+    // True when `target` shares its top bit with `pivot`, i.e. lies in pivot's half of the
+    // id space rather than the far half.
+    pub fn is_in_half_space(target: NodeId, pivot: NodeId) -> bool {
+        target.xor(pivot).data[0] & 0x80 == 0
+    }
+}
+
+// Node ids go on the wire as their 20 raw bytes.
+impl Bencodeable for NodeId {
+    fn bencode(self) -> BencodeObject {
+        BencodeObject::Bytes(self.to_bytes().to_vec())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use kademlia::NodeId;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use err::NodeIdError;
+    use std::collections::HashMap;
+    use std::{env, fs, mem, process};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+    use std::collections::BTreeMap;
+    use {BencodeObject, Bencodeable};
+    use temp_path;
+    use test_util::random_values;
+    use kademlia::{NodeId, Node, KBucket, RoutingTable, AddOutcome};
 
     #[test]
     fn test_plus_one_simple() {
@@ -96,11 +222,117 @@ mod tests {
         assert_eq!(node_id_plus_one, expected);
     }
 
+    #[test]
+    fn test_is_closer_than() {
+        let target = NodeId::new(0, 0, 0, 0, 0x80000000);
+        let near = NodeId::new(0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0x80000000);
+        let far = NodeId::new(0, 0, 0, 0, 0);
+        assert!(near.is_closer_than(far, target));
+        assert!(!far.is_closer_than(near, target));
+        assert!(!near.is_closer_than(near, target));
+        assert!(target.is_closer_than(near, target));
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        let id1 = NodeId::new(1, 0, 0, 0, 0);
+        let id2 = NodeId::new(0, 0, 0xffffffff, 0, 1);
+        assert_eq!(id1.hamming_distance(&id2), 34);
+    }
+
     #[test]
     fn test_distance() {
         let id1 = NodeId::new(1, 0, 0, 0, 0);
         let id2 = NodeId::new(0, 0, 0xffffffff, 0, 1);
-        assert_eq!(id1.distance(id2), 34);
+        assert_eq!(id1.distance(id2), NodeId::new(1, 0, 0xffffffff, 0, 1));
+        assert_eq!(id2.distance(id1), id1.distance(id2));
+        assert_eq!(id1.distance(id1), NodeId::ZERO);
+
+        // A single differing top bit outweighs every lower bit.
+        let near = NodeId::new(0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0);
+        let far = NodeId::new(0, 0, 0, 0, 0x80000000);
+        assert!(NodeId::ZERO.distance(near) < NodeId::ZERO.distance(far));
+        assert!(NodeId::ZERO.hamming_distance(&near) > NodeId::ZERO.hamming_distance(&far));
+    }
+
+    fn random_ids(seed: u64) -> impl Iterator<Item = NodeId> {
+        let mut random = random_values(seed);
+        (0..).map(move |_| {
+            let mut bytes = [0; 20];
+            for byte in bytes.iter_mut() {
+                *byte = random.next().unwrap() as u8;
+            }
+            NodeId::from_bytes(&bytes)
+        })
+    }
+
+    #[test]
+    fn test_distance_properties() {
+        let ids: Vec<NodeId> = random_ids(7).take(60).collect();
+        for a in &ids {
+            assert_eq!(a.distance(*a), NodeId::ZERO);
+            for b in &ids {
+                let ab = a.distance(*b);
+                assert_eq!(ab, b.distance(*a));
+                assert_eq!(ab == NodeId::ZERO, a == b);
+
+                let mut xor = [0; 20];
+                for (i, byte) in xor.iter_mut().enumerate() {
+                    *byte = a.to_bytes()[i] ^ b.to_bytes()[i];
+                }
+                assert_eq!(ab.to_bytes(), xor);
+
+                for c in ids.iter().take(10) {
+                    let (ac, bc) = (a.distance(*c), b.distance(*c));
+                    // The XOR triangle: d(a, c) = d(a, b) ^ d(b, c), which is at most their bitwise or.
+                    assert_eq!(ac, ab.xor(bc));
+                    let mut or = ab;
+                    for (i, byte) in or.data.iter_mut().enumerate() {
+                        *byte |= bc.data[i];
+                    }
+                    assert!(ac <= or);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_eq_bytes() {
+        let id = NodeId::from_bytes(b"abcdefghij0123456789");
+        assert!(id.eq_bytes(b"abcdefghij0123456789"));
+        assert!(!id.eq_bytes(b"abcdefghij012345678X"));
+        assert!(!id.eq_bytes(b"abcdefghij012345678"));
+        assert!(!id.eq_bytes(b"abcdefghij0123456789X"));
+        assert!(!id.eq_bytes(b""));
+    }
+
+    #[test]
+    fn test_bencode_node_id() {
+        let id = NodeId::from_bytes(b"abcdefghij0123456789");
+        let obj = bencode!["id" => id];
+        assert_eq!(obj.get_bytes_or("id", b""), b"abcdefghij0123456789");
+        assert_eq!(bencode![id].into_bytes(), b"l20:abcdefghij0123456789e".to_vec());
+    }
+
+    #[test]
+    fn test_signed_distance() {
+        let a = NodeId::new(0, 0, 0, 0, 0x0f);
+        let b = NodeId::new(0, 0, 0, 0, 0xf0);
+        assert_eq!(NodeId::signed_distance(a, b), (false, NodeId::new(0, 0, 0, 0, 0xff)));
+        assert_eq!(NodeId::signed_distance(b, a), (true, NodeId::new(0, 0, 0, 0, 0xff)));
+        assert_eq!(NodeId::signed_distance(a, a), (true, NodeId::ZERO));
+    }
+
+    #[test]
+    fn test_is_in_half_space() {
+        let low = NodeId::new(5, 0, 0, 0, 0x12345678);
+        let high = NodeId::new(0, 0, 0, 0, 0x80000001);
+        assert!(NodeId::is_in_half_space(NodeId::MIN, low));
+        assert!(NodeId::is_in_half_space(NodeId::new(0xffffffff, 0, 0, 0, 0x7fffffff), low));
+        assert!(!NodeId::is_in_half_space(NodeId::new(0, 0, 0, 0, 0x80000000), low));
+        assert!(!NodeId::is_in_half_space(NodeId::MAX, low));
+        assert!(NodeId::is_in_half_space(NodeId::MAX, high));
+        assert!(!NodeId::is_in_half_space(low, high));
     }
 
     #[test]
@@ -115,10 +347,602 @@ mod tests {
 
     #[test]
     fn test_midpoint_simple_2() {
-        let id1 = NodeId::new(0, 0, 0, 0, 0);
+        let id1 = NodeId::ZERO;
         let id2 = NodeId::new(0, 1, 0, 0, 0);
         assert_eq!(id1.midpoint(id2), NodeId::new(2147483648, 0, 0, 0, 0));
     }
+
+    #[test]
+    fn test_midpoint_carry_out() {
+        let mut half = NodeId::MAX;
+        half.data[0] = 0x7f;
+        assert_eq!(NodeId::MIN.midpoint(NodeId::MAX), half);
+        assert_eq!(NodeId::MAX.midpoint(NodeId::MAX), NodeId::MAX);
+        assert_eq!(NodeId::MAX.midpoint(NodeId::MAX.plus_one()), half);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let id = NodeId::new(0x11121314, 0x0d0e0f10, 0x090a0b0c, 0x05060708, 0x01020304);
+        let bytes = id.to_bytes();
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20]);
+        assert_eq!(NodeId::from_bytes(&bytes), id);
+    }
+
+    #[test]
+    fn test_from_magnet_urn_hex() {
+        let id = NodeId::from_magnet_urn("urn:btih:0123456789ABCDEF0123456789abcdef01234567").unwrap();
+        assert_eq!(id.to_bytes(), [
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23,
+            0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67
+        ]);
+        assert_eq!(NodeId::from_magnet_urn("xt=urn:btih:0123456789ABCDEF0123456789abcdef01234567").unwrap(), id);
+    }
+
+    #[test]
+    fn test_from_magnet_urn_base32() {
+        let hex = NodeId::from_magnet_urn("urn:btih:0123456789ABCDEF0123456789abcdef01234567").unwrap();
+        let base32 = NodeId::from_magnet_urn("urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(base32, hex);
+        assert_eq!(NodeId::from_magnet_urn("urn:btih:aeruKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap(), hex);
+    }
+
+    #[test]
+    fn test_from_magnet_urn_invalid() {
+        assert_eq!(NodeId::from_magnet_urn("urn:btih:0123"), Err(NodeIdError::Length(4)));
+        assert_eq!(NodeId::from_magnet_urn("urn:btih:0123456789ABCDEF0123456789abcdef0123456g"),
+                   Err(NodeIdError::Character(39)));
+        assert_eq!(NodeId::from_magnet_urn("urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRL1"),
+                   Err(NodeIdError::Character(31)));
+        assert_eq!(NodeId::from_magnet_urn("urn:sha1:0123456789ABCDEF0123456789abcdef01234567"),
+                   Err(NodeIdError::Urn));
+    }
+
+    #[test]
+    fn test_ordering_most_significant_word_first() {
+        assert!(NodeId::new(0, 1, 0, 0, 0) > NodeId::new(0xffffffff, 0, 0, 0, 0));
+        assert!(NodeId::new(0, 0, 0, 0, 1) > NodeId::new(0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0));
+        assert!(NodeId::new(1, 0, 0, 0, 0) < NodeId::new(2, 0, 0, 0, 0));
+        let id = NodeId::new(5, 6, 7, 8, 9);
+        assert!(id < id.plus_one());
+    }
+
+    #[test]
+    fn test_contains_id_in_range() {
+        let low = NodeId::new(0xffffffff, 0, 0, 0, 0);
+        let high = NodeId::new(0, 0, 0, 0, 1);
+        let bucket = KBucket {
+            k_size: 8,
+            range: (low, high),
+            nodes: HashMap::new()
+        };
+
+        assert!(bucket.contains_id_in_range(low));
+        assert!(bucket.contains_id_in_range(high));
+        assert!(bucket.contains_id_in_range(low.plus_one()));
+        assert!(bucket.contains_id_in_range(low.midpoint(high)));
+        assert!(!bucket.contains_id_in_range(NodeId::new(0xfffffffe, 0, 0, 0, 0)));
+        assert!(!bucket.contains_id_in_range(high.plus_one()));
+        assert!(!bucket.contains_id_in_range(NodeId::MIN));
+        assert!(!bucket.contains_id_in_range(NodeId::MAX));
+    }
+
+    #[test]
+    fn test_min_max() {
+        assert!(NodeId::MIN < NodeId::MAX);
+        assert_eq!(NodeId::ZERO, NodeId::MIN);
+        assert_eq!(NodeId::MAX.plus_one(), NodeId::MIN);
+
+        let ids = [
+            NodeId::new(1, 0, 0, 0, 0),
+            NodeId::new(0, 0, 0, 0, 1),
+            NodeId::new(0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff, 0xfffffffe),
+            NodeId::new(0xfffffffe, 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff),
+            NodeId::MIN,
+            NodeId::MAX,
+        ];
+        for id in ids.iter() {
+            assert!(*id >= NodeId::MIN);
+            assert!(*id <= NodeId::MAX);
+        }
+    }
+
+    #[test]
+    fn test_routing_table_new() {
+        let node = Node {
+            ip_address: Ipv4Addr::new(127, 0, 0, 1),
+            port: 1234,
+            id: NodeId::new(1, 2, 3, 4, 5)
+        };
+        let table = RoutingTable::new(node, 8);
+        assert_eq!(table.buckets.len(), 1);
+        assert_eq!(table.buckets[0].range, (NodeId::MIN, NodeId::MAX));
+    }
+
+    #[test]
+    fn test_remove_by_addr() {
+        let own = Node::from((NodeId::new(1, 2, 3, 4, 5), SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1234)));
+        let mut table = RoutingTable::new(own, 8);
+        let addr1 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let addr2 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881);
+        table.add(Node::from((NodeId::new(7, 0, 0, 0, 0), addr1)));
+        table.add(Node::from((NodeId::new(8, 0, 0, 0, 0), addr2)));
+
+        let removed = table.remove_by_addr(addr1).unwrap();
+        assert_eq!(removed.id, NodeId::new(7, 0, 0, 0, 0));
+        assert!(table.remove_by_addr(addr1).is_none());
+
+        let remaining: Vec<NodeId> = table.buckets.iter().flat_map(|b| b.nodes.keys().cloned()).collect();
+        assert_eq!(remaining, vec![NodeId::new(8, 0, 0, 0, 0)]);
+    }
+
+    // A file in a directory of its own, which the test removes with remove_table_dir.
+    fn table_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kademlia-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn remove_table_dir(path: &Path) {
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    fn node(id: NodeId, last_octet: u8) -> Node {
+        Node::from((id, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, last_octet), 6881)))
+    }
+
+    fn node_ids(table: &RoutingTable) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = table.buckets.iter().flat_map(|b| b.nodes.keys().cloned()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_node_count() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        assert_eq!(table.node_count(), 0);
+        assert_eq!(table.bucket_count(), 1);
+        let empty_size = table.size_in_bytes();
+
+        for i in 0..20u32 {
+            table.add(node(NodeId::new(0, 0, 0, 0, i.wrapping_mul(0x0c000000)), i as u8));
+        }
+        assert_eq!(table.node_count(), 20);
+        assert!(table.bucket_count() > 1);
+        assert_eq!(table.bucket_count(), table.buckets.len());
+        assert!(table.size_in_bytes() >= empty_size + 20 * mem::size_of::<Node>());
+
+        table.add(node(NodeId::new(0, 0, 0, 0, 0x0c000000), 1));
+        assert_eq!(table.node_count(), 20);
+        table.remove_by_addr(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881));
+        assert_eq!(table.node_count(), 19);
+    }
+
+    #[test]
+    fn test_find_closest_excludes_self() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        match table.add(own) {
+            AddOutcome::IsSelf => (),
+            other => panic!("expected IsSelf, got {:?}", other),
+        }
+        assert_eq!(table.node_count(), 0);
+
+        for i in 1..6u32 {
+            table.add(node(NodeId::new(1, 2, 3, 4, 5 + (i << 24)), i as u8));
+        }
+        let closest: Vec<NodeId> = table.find_closest(own.id, 3).iter().map(|node| node.id).collect();
+        assert_eq!(closest, vec![
+            NodeId::new(1, 2, 3, 4, 5 + (1 << 24)),
+            NodeId::new(1, 2, 3, 4, 5 + (2 << 24)),
+            NodeId::new(1, 2, 3, 4, 5 + (3 << 24)),
+        ]);
+
+        // Even a stray self entry (e.g. from an old table file) is never returned.
+        let bucket_index = table.get_bucket_for(&own);
+        table.buckets[bucket_index].add(own, Instant::now());
+        assert!(table.find_closest(own.id, 10).iter().all(|node| node.id != own.id));
+        assert_eq!(table.find_closest(own.id, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_closest_good() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        table.add(own);
+        for i in 1..6u32 {
+            table.add(node(NodeId::new(1, 2, 3, 4, 5 + (i << 24)), i as u8));
+        }
+        let id = |i: u32| NodeId::new(1, 2, 3, 4, 5 + (i << 24));
+        // The closest contact is questionable, the next one bad.
+        table.mark_failed(id(1));
+        table.mark_failed(id(2));
+        table.mark_failed(id(2));
+
+        let ids = |nodes: Vec<Node>| nodes.iter().map(|node| node.id).collect::<Vec<_>>();
+        assert_eq!(ids(table.closest_good(own.id, 2)), vec![id(3), id(4)]);
+        assert_eq!(ids(table.closest_good(own.id, 10)), vec![id(3), id(4), id(5), id(1)]);
+        assert_eq!(ids(table.find_closest(own.id, 2)), vec![id(1), id(2)]);
+    }
+
+    #[test]
+    fn test_is_bootstrapped() {
+        let own = node(NodeId::new(0, 0, 0, 0, 0x10000000), 1);
+        let mut table = RoutingTable::new(own, 2);
+        assert!(!table.is_bootstrapped(3));
+
+        // Far from our id: these end up in the upper half, away from our bucket.
+        table.add(node(NodeId::new(0, 0, 0, 0, 0xc0000000), 2));
+        table.add(node(NodeId::new(0, 0, 0, 0, 0xd0000000), 3));
+        table.add(node(NodeId::new(0, 0, 0, 0, 0xe0000000), 4));
+        assert!(!table.is_bootstrapped(3));
+
+        table.add(node(NodeId::new(0, 0, 0, 0, 0x10000001), 5));
+        table.add(node(NodeId::new(0, 0, 0, 0, 0x18000000), 6));
+        table.add(node(NodeId::new(0, 0, 0, 0, 0x20000000), 7));
+        assert!(table.is_bootstrapped(3));
+
+        // One unanswered query is enough to stop counting a contact, even though it is not bad yet.
+        table.mark_failed(NodeId::new(0, 0, 0, 0, 0x10000001));
+        assert!(!table.is_bootstrapped(3));
+
+        table.mark_responded(NodeId::new(0, 0, 0, 0, 0x10000001), Instant::now());
+        assert!(table.is_bootstrapped(3));
+    }
+
+    #[test]
+    fn test_depth_histogram() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 1);
+        assert_eq!(table.depth_histogram().into_iter().collect::<Vec<_>>(), vec![(0, 1)]);
+
+        for (i, &top) in [0x10000000u32, 0x90000000, 0x30000000].iter().enumerate() {
+            table.add(node(NodeId::new(0, 0, 0, 0, top), i as u8));
+        }
+        // [0, 1f..] and [20.., 3f..] at depth 3, [40.., 7f..] at 2, [80.., ff..] at 1.
+        assert_eq!(table.depth_histogram().into_iter().collect::<Vec<_>>(), vec![(1, 1), (2, 1), (3, 2)]);
+        assert_eq!(table.buckets[0].depth(), 3);
+        assert_eq!(table.buckets[3].depth(), 1);
+
+        let narrow = KBucket {
+            k_size: 8,
+            range: (NodeId::new(6, 0, 0, 0, 0), NodeId::new(7, 0, 0, 0, 0)),
+            nodes: HashMap::new()
+        };
+        assert_eq!(narrow.depth(), 159);
+    }
+
+    #[test]
+    fn test_kbucket_add_outcome() {
+        let mut bucket = KBucket {
+            k_size: 2,
+            range: (NodeId::MIN, NodeId::MAX),
+            nodes: HashMap::new()
+        };
+        let now = Instant::now();
+        match bucket.add(node(NodeId::new(7, 0, 0, 0, 0), 2), now) {
+            AddOutcome::Inserted => (),
+            other => panic!("expected Inserted, got {:?}", other),
+        }
+        bucket.add(node(NodeId::new(8, 0, 0, 0, 0), 3), now);
+        match bucket.add(node(NodeId::new(7, 0, 0, 0, 0), 9), now) {
+            AddOutcome::Updated(old) => assert_eq!(old.ip_address, Ipv4Addr::new(10, 0, 0, 2)),
+            other => panic!("expected Updated, got {:?}", other),
+        }
+        match bucket.add(node(NodeId::new(9, 0, 0, 0, 0), 4), now) {
+            AddOutcome::Full => (),
+            other => panic!("expected Full, got {:?}", other),
+        }
+        assert_eq!(bucket.nodes.len(), 2);
+
+        for _ in 0..2 {
+            bucket.nodes.get_mut(&NodeId::new(8, 0, 0, 0, 0)).unwrap().fail_count += 1;
+        }
+        bucket.add(node(NodeId::new(8, 0, 0, 0, 0), 3), now);
+        assert!(bucket.nodes[&NodeId::new(8, 0, 0, 0, 0)].is_bad());
+        match bucket.add(node(NodeId::new(9, 0, 0, 0, 0), 4), now) {
+            AddOutcome::Replaced(evicted) => assert_eq!(evicted.id, NodeId::new(8, 0, 0, 0, 0)),
+            other => panic!("expected Replaced, got {:?}", other),
+        }
+        assert!(bucket.nodes.contains_key(&NodeId::new(9, 0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn test_bad_node_evicted() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 2);
+        let mut bucket = KBucket {
+            k_size: 2,
+            range: (NodeId::MIN, NodeId::MAX),
+            nodes: HashMap::new()
+        };
+        let now = Instant::now();
+        bucket.add(node(NodeId::new(7, 0, 0, 0, 0), 2), now);
+        bucket.add(node(NodeId::new(8, 0, 0, 0, 0), 3), now);
+        table.buckets = vec![bucket];
+        table.max_buckets = 1;
+
+        // A full bucket of good nodes has no room.
+        table.add(node(NodeId::new(9, 0, 0, 0, 0), 4));
+        assert_eq!(node_ids(&table), vec![NodeId::new(7, 0, 0, 0, 0), NodeId::new(8, 0, 0, 0, 0)]);
+
+        assert!(table.mark_failed(NodeId::new(7, 0, 0, 0, 0)));
+        assert!(!table.entry_mut(NodeId::new(7, 0, 0, 0, 0)).unwrap().is_bad());
+        assert!(table.mark_responded(NodeId::new(7, 0, 0, 0, 0), now));
+        assert!(table.mark_failed(NodeId::new(7, 0, 0, 0, 0)));
+        assert!(!table.entry_mut(NodeId::new(7, 0, 0, 0, 0)).unwrap().is_bad());
+        assert!(table.mark_failed(NodeId::new(7, 0, 0, 0, 0)));
+        assert!(table.entry_mut(NodeId::new(7, 0, 0, 0, 0)).unwrap().is_bad());
+        assert!(!table.mark_failed(NodeId::new(9, 0, 0, 0, 0)));
+
+        table.add(node(NodeId::new(9, 0, 0, 0, 0), 4));
+        assert_eq!(node_ids(&table), vec![NodeId::new(8, 0, 0, 0, 0), NodeId::new(9, 0, 0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_spiral_from() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 2);
+        for (i, &top) in [0x10000000u32, 0x20000000, 0x50000000, 0x60000000, 0x90000000, 0xa0000000, 0xd0000000].iter().enumerate() {
+            table.add(node(NodeId::new(0, 0, 0, 0, top), i as u8));
+        }
+        let target = NodeId::new(0, 0, 0, 0, 0x58000000);
+        let bucket = &table.buckets[table.buckets.iter().position(|b| b.contains_id_in_range(target)).unwrap()];
+
+        let spiral: Vec<NodeId> = table.spiral_from(target).map(|node| node.id).collect();
+        assert_eq!(spiral.len(), table.node_count());
+        assert!(!bucket.nodes.is_empty());
+        for id in &spiral[..bucket.nodes.len()] {
+            assert!(bucket.contains_id_in_range(*id));
+        }
+
+        let mut sorted = spiral.clone();
+        sorted.sort();
+        assert_eq!(sorted, node_ids(&table));
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        let start = Instant::now();
+        table.add_seen(node(NodeId::new(7, 0, 0, 0, 0), 2), start);
+        table.add_seen(node(NodeId::new(8, 0, 0, 0, 0), 3), start + Duration::from_secs(600));
+        table.add_seen(node(NodeId::new(9, 0, 0, 0, 0), 4), start);
+        // Seeing a node again refreshes it.
+        table.add_seen(node(NodeId::new(9, 0, 0, 0, 0), 4), start + Duration::from_secs(700));
+
+        let max_age = Duration::from_secs(900);
+        assert!(table.sweep_expired(start + Duration::from_secs(900), max_age).is_empty());
+        assert_eq!(table.sweep_expired(start + Duration::from_secs(1000), max_age), vec![NodeId::new(7, 0, 0, 0, 0)]);
+        assert_eq!(node_ids(&table), vec![NodeId::new(8, 0, 0, 0, 0), NodeId::new(9, 0, 0, 0, 0)]);
+
+        let mut swept = table.sweep_expired(start + Duration::from_secs(2000), max_age);
+        swept.sort();
+        assert_eq!(swept, vec![NodeId::new(8, 0, 0, 0, 0), NodeId::new(9, 0, 0, 0, 0)]);
+        assert_eq!(table.node_count(), 0);
+    }
+
+    #[test]
+    fn test_pinned_node_survives_sweep() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        let start = Instant::now();
+        table.add_seen(node(NodeId::new(7, 0, 0, 0, 0), 2), start);
+        table.add_seen(node(NodeId::new(8, 0, 0, 0, 0), 3), start);
+        assert!(table.pin(NodeId::new(8, 0, 0, 0, 0)));
+        assert!(!table.pin(NodeId::new(9, 0, 0, 0, 0)));
+        // Seeing a pinned node again keeps it pinned.
+        table.add_seen(node(NodeId::new(8, 0, 0, 0, 0), 4), start);
+
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(table.sweep_expired(later, Duration::from_secs(900)), vec![NodeId::new(7, 0, 0, 0, 0)]);
+        assert_eq!(node_ids(&table), vec![NodeId::new(8, 0, 0, 0, 0)]);
+
+        assert!(table.unpin(NodeId::new(8, 0, 0, 0, 0)));
+        assert_eq!(table.sweep_expired(later, Duration::from_secs(900)), vec![NodeId::new(8, 0, 0, 0, 0)]);
+        assert_eq!(table.node_count(), 0);
+    }
+
+    #[test]
+    fn test_pinned_node_not_evicted() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 2);
+        table.max_buckets = 1;
+        table.add(node(NodeId::new(7, 0, 0, 0, 0), 2));
+        table.add(node(NodeId::new(8, 0, 0, 0, 0), 3));
+        assert!(table.pin(NodeId::new(7, 0, 0, 0, 0)));
+        for _ in 0..2 {
+            table.mark_failed(NodeId::new(7, 0, 0, 0, 0));
+        }
+
+        match table.add(node(NodeId::new(9, 0, 0, 0, 0), 4)) {
+            AddOutcome::Full => (),
+            other => panic!("expected Full, got {:?}", other),
+        }
+        assert!(table.unpin(NodeId::new(7, 0, 0, 0, 0)));
+        match table.add(node(NodeId::new(9, 0, 0, 0, 0), 4)) {
+            AddOutcome::Replaced(evicted) => assert_eq!(evicted.id, NodeId::new(7, 0, 0, 0, 0)),
+            other => panic!("expected Replaced, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_buckets_ordered_after_splits() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 1);
+        for (i, &top) in [0xf0000000u32, 0x10000000, 0x90000000, 0x30000000, 0x70000000, 0x50000000].iter().enumerate() {
+            table.add(node(NodeId::new(0, 0, 0, 0, top), i as u8));
+        }
+        assert!(table.bucket_count() > 4);
+        assert!(table.buckets_are_ordered());
+
+        table.buckets.swap(1, 2);
+        assert!(!table.buckets_are_ordered());
+    }
+
+    #[test]
+    fn test_full_far_bucket_does_not_split() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 2);
+        // Our bucket, and a far one at depth 5 covering ids that start with five 1 bits.
+        let far_start = NodeId::new(0, 0, 0, 0, 0xf8000000);
+        let mut near_end = NodeId::MAX;
+        near_end.data[0] = 0xf7;
+        table.buckets = vec![
+            KBucket { k_size: 2, range: (NodeId::MIN, near_end), nodes: HashMap::new() },
+            KBucket { k_size: 2, range: (far_start, NodeId::MAX), nodes: HashMap::new() },
+        ];
+        assert_eq!(table.buckets[1].depth(), 5);
+
+        for i in 1..4u32 {
+            table.add(node(NodeId::new(i, 0, 0, 0, 0xff000000), i as u8));
+        }
+        assert_eq!(table.bucket_count(), 2);
+        assert_eq!(table.buckets[1].nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 2);
+        // Flips the given bits of our own id, counting from the least significant.
+        let near = |bits: &[usize]| {
+            let mut id = own.id;
+            for &bit in bits {
+                id.data[19 - bit / 8] ^= 1 << (bit % 8);
+            }
+            id
+        };
+        // Only our own bucket keeps splitting, so contacts sharing ever longer prefixes with
+        // us are what drive the table to its full depth.
+        for bit in (0..160).rev() {
+            for &low in [0, 1, 2].iter() {
+                table.add(node(near(&[bit, low]), 2));
+            }
+            table.add(node(near(&[bit]), 3));
+            assert!(table.bucket_count() <= 160);
+        }
+        assert_eq!(table.bucket_count(), 160);
+
+        // Random contacts are almost all far from us, so they split far fewer buckets.
+        let mut table = RoutingTable::new(own, 2);
+        let mut state = 0x2545f491u32;
+        for i in 0..5000u32 {
+            let mut data = [0; 5];
+            for word in data.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *word = state;
+            }
+            table.add(node(NodeId::new(data[0], data[1], data[2], data[3], data[4]), i as u8));
+        }
+        assert!(table.bucket_count() < 80, "{} buckets", table.bucket_count());
+
+        let mut table = RoutingTable::new(own, 1);
+        table.max_buckets = 3;
+        for i in 1..100u32 {
+            table.add(node(NodeId::new(0, 0, 0, 0, i << 24), i as u8));
+        }
+        assert_eq!(table.bucket_count(), 3);
+        assert!(table.node_count() <= 3);
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let node = node(NodeId::new(1, 2, 3, 4, 5), 7);
+        let compact = node.to_compact();
+        assert_eq!(&compact[20..], &[10, 0, 0, 7, 0x1a, 0xe1]);
+        let decoded = Node::from_compact(&compact).unwrap();
+        assert_eq!(decoded.id, node.id);
+        assert_eq!(SocketAddrV4::from(&decoded), SocketAddrV4::from(&node));
+        assert!(Node::from_compact(&compact[1..]).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = table_path("save_and_load");
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        table.add(node(NodeId::new(7, 0, 0, 0, 0), 2));
+        table.add(node(NodeId::new(8, 0, 0, 0, 9), 3));
+        table.save_atomic(&path).unwrap();
+
+        let loaded = RoutingTable::load_or_new(&path, own, 8).unwrap();
+        assert_eq!(node_ids(&loaded), node_ids(&table));
+
+        let other = node(NodeId::new(9, 9, 9, 9, 9), 1);
+        assert!(node_ids(&RoutingTable::load_or_new(&path, other, 8).unwrap()).is_empty());
+        remove_table_dir(&path);
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let path = table_path("missing");
+        let table = RoutingTable::load_or_new(&path, own, 8).unwrap();
+        assert_eq!(table.buckets.len(), 1);
+        assert!(node_ids(&table).is_empty());
+        remove_table_dir(&path);
+    }
+
+    #[test]
+    fn test_load_after_interrupted_save() {
+        let path = table_path("interrupted");
+        let own = node(NodeId::new(1, 2, 3, 4, 5), 1);
+        let mut table = RoutingTable::new(own, 8);
+        table.add(node(NodeId::new(7, 0, 0, 0, 0), 2));
+        table.save_atomic(&path).unwrap();
+
+        // A save that died halfway through, or one still in progress elsewhere, leaves a
+        // partial temp file next to the real one. Loading ignores it and leaves it in place.
+        let temp = temp_path(&path);
+        fs::write(&temp, &own.to_compact()[..10]).unwrap();
+
+        let loaded = RoutingTable::load_or_new(&path, own, 8).unwrap();
+        assert_eq!(node_ids(&loaded), vec![NodeId::new(7, 0, 0, 0, 0)]);
+        assert!(temp.exists());
+
+        // The next save uses a temp file of its own.
+        table.add(node(NodeId::new(8, 0, 0, 0, 0), 3));
+        table.save_atomic(&path).unwrap();
+        assert_eq!(fs::read(&temp).unwrap(), &own.to_compact()[..10]);
+        assert_eq!(node_ids(&RoutingTable::load_or_new(&path, own, 8).unwrap()), node_ids(&table));
+        remove_table_dir(&path);
+    }
+
+    #[test]
+    fn test_temp_path_unique() {
+        let path = PathBuf::from("/data/table.dat");
+        let (a, b) = (temp_path(&path), temp_path(&path));
+        assert_ne!(a, b);
+        assert_eq!(a.parent(), path.parent());
+        let name = a.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(&format!("table.dat.{}.", process::id())) && name.ends_with(".tmp"), "{}", name);
+    }
+
+    #[test]
+    fn test_node_from_socket_addr() {
+        let id = NodeId::new(1, 2, 3, 4, 5);
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let node = Node::from((id, addr));
+        assert_eq!(node.id, id);
+        assert_eq!(node.ip_address, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(node.port, 6881);
+    }
+
+    #[test]
+    fn test_socket_addr_from_node() {
+        let node = Node {
+            ip_address: Ipv4Addr::new(127, 0, 0, 1),
+            port: 1234,
+            id: NodeId::new(1, 0, 0, 0, 0)
+        };
+        let addr: SocketAddrV4 = (&node).into();
+        assert_eq!(addr, SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1234));
+    }
 }
 
 
@@ -130,20 +954,120 @@ pub struct Node {
 }
 
 impl Node {
-    fn distance(&self, other: Node) -> u32 {
+    fn distance(&self, other: Node) -> NodeId {
         self.id.distance(other.id)
     }
+
+    // BEP 5 "compact node info": the 20-byte id, then the IPv4 address and port in network
+    // byte order.
+    pub fn to_compact(self) -> [u8; 26] {
+        let mut bytes = [0; 26];
+        bytes[..20].copy_from_slice(&self.id.data);
+        bytes[20..24].copy_from_slice(&self.ip_address.octets());
+        bytes[24] = (self.port >> 8) as u8;
+        bytes[25] = self.port as u8;
+        bytes
+    }
+
+    pub fn from_compact(bytes: &[u8]) -> Option<Node> {
+        if bytes.len() != 26 {
+            return None;
+        }
+        let mut id = [0; 20];
+        id.copy_from_slice(&bytes[..20]);
+        Some(Node {
+            ip_address: Ipv4Addr::new(bytes[20], bytes[21], bytes[22], bytes[23]),
+            port: (bytes[24] as u16) << 8 | bytes[25] as u16,
+            id: NodeId::from_bytes(&id)
+        })
+    }
+}
+
+impl From<(NodeId, SocketAddrV4)> for Node {
+    fn from((id, addr): (NodeId, SocketAddrV4)) -> Node {
+        Node {
+            ip_address: *addr.ip(),
+            port: addr.port(),
+            id
+        }
+    }
+}
+
+impl<'a> From<&'a Node> for SocketAddrV4 {
+    fn from(node: &'a Node) -> SocketAddrV4 {
+        SocketAddrV4::new(node.ip_address, node.port)
+    }
+}
+
+// BEP 5: a node that fails to respond to this many queries in a row is bad.
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Clone, Copy, Debug)]
+struct NodeEntry {
+    node: Node,
+    last_seen: Instant,
+    // Consecutive queries the node failed to answer.
+    fail_count: u32,
+    // Never evicted or swept, e.g. a known-good bootstrap router. Still takes up a slot.
+    pinned: bool
+}
+
+impl NodeEntry {
+    fn is_bad(&self) -> bool {
+        self.fail_count >= MAX_FAILURES
+    }
+
+    fn is_good(&self) -> bool {
+        self.fail_count == 0
+    }
+}
+
+// What adding a node did, including any contact that left the bucket so the caller can
+// follow up (e.g. ping a replacement or report an eviction).
+#[derive(Debug)]
+enum AddOutcome {
+    Inserted,
+    // Already present; holds the entry's previous contact info.
+    Updated(Node),
+    // A bad node was evicted to make room; holds the evicted node.
+    Replaced(Node),
+    // No room, so the node was not added.
+    Full,
+    // The node is us, and was not added.
+    IsSelf,
 }
 
 struct KBucket {
     k_size: u32,
     range: (NodeId, NodeId),
-    nodes: HashMap<NodeId, Node>
+    nodes: HashMap<NodeId, NodeEntry>
 }
 
 impl KBucket {
-    fn add(&mut self, node: Node) -> bool {
-        true
+    // Adding a node that is already present refreshes its last_seen time. When the bucket is
+    // full, a bad node is evicted to make room.
+    fn add(&mut self, node: Node, now: Instant) -> AddOutcome {
+        let mut entry = NodeEntry { node, last_seen: now, fail_count: 0, pinned: false };
+        // Hearing about a known node doesn't mean it answers us, so its failures stand until
+        // mark_responded.
+        if let Some(old) = self.nodes.get(&node.id).copied() {
+            entry.fail_count = old.fail_count;
+            entry.pinned = old.pinned;
+            self.nodes.insert(node.id, entry);
+            return AddOutcome::Updated(old.node);
+        }
+        if (self.nodes.len() as u32) < self.k_size {
+            self.nodes.insert(node.id, entry);
+            return AddOutcome::Inserted;
+        }
+        let bad = self.nodes.values().find(|entry| entry.is_bad() && !entry.pinned).map(|entry| entry.node.id);
+        match bad.and_then(|id| self.nodes.remove(&id)) {
+            Some(evicted) => {
+                self.nodes.insert(node.id, entry);
+                AddOutcome::Replaced(evicted.node)
+            },
+            None => AddOutcome::Full,
+        }
     }
 
     fn split(&self) -> (KBucket, KBucket) {
@@ -160,64 +1084,313 @@ impl KBucket {
             nodes: HashMap::new()
         };
 
-        for (node_id, node) in &self.nodes {
+        for (node_id, entry) in &self.nodes {
             if *node_id <= bucket1.range.1 {
-                bucket1.nodes.insert(*node_id, *node);
+                bucket1.nodes.insert(*node_id, *entry);
             } else {
-                bucket2.nodes.insert(*node_id, *node);
+                bucket2.nodes.insert(*node_id, *entry);
             }
         }
 
-        return (bucket1, bucket2);
+        (bucket1, bucket2)
     }
 
-    fn has_in_range(&self, node: Node) -> bool {
-        node.id >= self.range.0 && node.id <= self.range.1
+    // Both ends of the range are inclusive.
+    fn contains_id_in_range(&self, id: NodeId) -> bool {
+        id >= self.range.0 && id <= self.range.1
     }
 
+    // Number of leading id bits shared by every id in the bucket. Splits halve ranges
+    // exactly, so this is how many splits it took to get here.
     fn depth(&self) -> u32 {
-        0
+        self.range.0.xor(self.range.1).leading_zeros()
     }
 }
 
+// 160 buckets already separate every possible prefix length of a 160-bit id.
+const DEFAULT_MAX_BUCKETS: usize = 160;
+
 pub struct RoutingTable {
     node: Node,
-    buckets: Vec<KBucket>
+    buckets: Vec<KBucket>,
+    max_buckets: usize
 }
 
 impl RoutingTable {
-    fn add(&mut self, node: Node) {
+    fn new(node: Node, k_size: u32) -> RoutingTable {
+        RoutingTable {
+            node,
+            buckets: vec![KBucket {
+                k_size,
+                range: (NodeId::MIN, NodeId::MAX),
+                nodes: HashMap::new()
+            }],
+            max_buckets: DEFAULT_MAX_BUCKETS
+        }
+    }
+
+    // Writes the table next to `path` and renames it into place, so a crash mid-write leaves
+    // either the old file or the new one, never a torn one. The file is our own compact node
+    // info followed by that of every contact.
+    fn save_atomic(&self, path: &Path) -> Result<(), io::Error> {
+        let temp_path = temp_path(path);
+        let result = self.write_compact(&temp_path).and_then(|()| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn write_compact(&self, path: &Path) -> Result<(), io::Error> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.node.to_compact())?;
+        for bucket in &self.buckets {
+            for entry in bucket.nodes.values() {
+                file.write_all(&entry.node.to_compact())?;
+            }
+        }
+        file.sync_all()
+    }
+
+    // Loads a table saved by save_atomic, or starts a fresh one if there is no saved table or
+    // it belongs to a different node id. Temp files are left alone, since another save may
+    // still be writing one.
+    fn load_or_new(path: &Path, node: Node, k_size: u32) -> Result<RoutingTable, io::Error> {
+        let mut table = RoutingTable::new(node, k_size);
+        let mut bytes = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => { file.read_to_end(&mut bytes)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(err) => return Err(err),
+        }
+
+        if bytes.len() % 26 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "routing table file is truncated"));
+        }
+        let mut entries = bytes.chunks(26).filter_map(Node::from_compact);
+        match entries.next() {
+            Some(saved) if saved.id == node.id => (),
+            _ => return Ok(table),
+        }
+        for entry in entries {
+            table.add(entry);
+        }
+        Ok(table)
+    }
+
+    fn add(&mut self, node: Node) -> AddOutcome {
+        self.add_seen(node, Instant::now())
+    }
+
+    // Our own id is never stored, so it can't be handed out as a contact.
+    fn add_seen(&mut self, node: Node, now: Instant) -> AddOutcome {
+        if node.id == self.node.id {
+            return AddOutcome::IsSelf;
+        }
         let bucket_index = self.get_bucket_for(&node);
 
-        if self.buckets[bucket_index].add(node.clone()) {
-            return;
+        match self.buckets[bucket_index].add(node, now) {
+            AddOutcome::Full => (),
+            outcome => return outcome,
         }
 
         let should_split: bool = {
-            let ref bucket = self.buckets[bucket_index];
-            bucket.has_in_range(node) || bucket.depth() % 5 != 0
+            let bucket = &self.buckets[bucket_index];
+            bucket.contains_id_in_range(self.node.id) || bucket.depth() % 5 != 0
         };
 
-        if should_split {
-            self.split_bucket(bucket_index);
-            self.add(node);
+        if should_split && self.split_bucket(bucket_index) {
+            self.add_seen(node, now)
         } else {
             // TODO
+            AddOutcome::Full
+        }
+    }
+
+    // Whether our own bucket and the buckets on either side of it hold at least `min_close`
+    // good contacts, i.e. we know enough of our neighbourhood to stop bootstrapping hard.
+    fn is_bootstrapped(&self, min_close: usize) -> bool {
+        let own = self.get_bucket_for(&self.node);
+        let first = own.saturating_sub(1);
+        let last = (own + 1).min(self.buckets.len() - 1);
+        let good = self.buckets[first..=last].iter()
+            .flat_map(|bucket| bucket.nodes.values())
+            .filter(|entry| entry.is_good())
+            .count();
+        good >= min_close
+    }
+
+    fn depth_histogram(&self) -> BTreeMap<u32, usize> {
+        let mut histogram = BTreeMap::new();
+        for bucket in &self.buckets {
+            *histogram.entry(bucket.depth()).or_insert(0) += 1;
         }
+        histogram
+    }
+
+    fn node_count(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    // Rough memory footprint: the buckets themselves plus their stored contacts. Hash map
+    // overhead beyond the entries is not counted.
+    fn size_in_bytes(&self) -> usize {
+        mem::size_of::<RoutingTable>()
+            + self.buckets.capacity() * mem::size_of::<KBucket>()
+            + self.buckets.iter()
+                .map(|bucket| bucket.nodes.capacity() * (mem::size_of::<NodeId>() + mem::size_of::<NodeEntry>()))
+                .sum::<usize>()
+    }
+
+    // Linear scan over every bucket; only for when all we have is the address.
+    fn remove_by_addr(&mut self, addr: SocketAddrV4) -> Option<Node> {
+        for bucket in self.buckets.iter_mut() {
+            let found = bucket.nodes.values()
+                .find(|entry| SocketAddrV4::from(&entry.node) == addr)
+                .map(|entry| entry.node.id);
+            if let Some(id) = found {
+                return bucket.nodes.remove(&id).map(|entry| entry.node);
+            }
+        }
+        None
+    }
+
+    // Records a query the node didn't answer. Returns false if the node isn't in the table.
+    fn mark_failed(&mut self, id: NodeId) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                entry.fail_count += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn mark_responded(&mut self, id: NodeId, now: Instant) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                entry.fail_count = 0;
+                entry.last_seen = now;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Protects a contact from eviction and sweeping until unpinned. Returns false if the node
+    // isn't in the table.
+    fn pin(&mut self, id: NodeId) -> bool {
+        self.set_pinned(id, true)
+    }
+
+    fn unpin(&mut self, id: NodeId) -> bool {
+        self.set_pinned(id, false)
+    }
+
+    fn set_pinned(&mut self, id: NodeId, pinned: bool) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                entry.pinned = pinned;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn entry_mut(&mut self, id: NodeId) -> Option<&mut NodeEntry> {
+        self.buckets.iter_mut()
+            .find(|bucket| bucket.contains_id_in_range(id))
+            .and_then(|bucket| bucket.nodes.get_mut(&id))
+    }
+
+    // Drops every contact not seen within `max_age`, returning their ids.
+    fn sweep_expired(&mut self, now: Instant, max_age: Duration) -> Vec<NodeId> {
+        let mut expired = Vec::new();
+        for bucket in self.buckets.iter_mut() {
+            bucket.nodes.retain(|id, entry| {
+                let keep = entry.pinned || now.saturating_duration_since(entry.last_seen) <= max_age;
+                if !keep {
+                    expired.push(*id);
+                }
+                keep
+            });
+        }
+        expired
+    }
+
+    // Up to `count` contacts closest to `target` by XOR distance, closest first. Never
+    // includes our own node.
+    fn find_closest(&self, target: NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.values().map(|entry| entry.node))
+            .filter(|node| node.id != self.node.id)
+            .collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    // Like find_closest, but for answering queries: the closest good contacts (no unanswered
+    // queries) come first, then questionable ones if there aren't `count` good ones. Bad
+    // contacts are left out.
+    fn closest_good(&self, target: NodeId, count: usize) -> Vec<Node> {
+        let mut entries: Vec<&NodeEntry> = self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.values())
+            .filter(|entry| entry.node.id != self.node.id && !entry.is_bad())
+            .collect();
+        entries.sort_by_key(|entry| (!entry.is_good(), entry.node.id.distance(target)));
+        entries.iter().take(count).map(|entry| entry.node).collect()
+    }
+
+    // Nodes bucket by bucket moving outward from the target's bucket, alternating below and
+    // above it. Nodes within a bucket come in no particular order.
+    fn spiral_from(&self, target: NodeId) -> impl Iterator<Item = &Node> {
+        let start = self.buckets.iter().position(|bucket| bucket.contains_id_in_range(target)).unwrap_or(0);
+        let mut order = vec![start];
+        for step in 1..self.buckets.len() {
+            if step <= start {
+                order.push(start - step);
+            }
+            if start + step < self.buckets.len() {
+                order.push(start + step);
+            }
+        }
+        order.into_iter().flat_map(move |i| self.buckets[i].nodes.values().map(|entry| &entry.node))
     }
 
     fn get_bucket_for(&self, node: &Node) -> usize {
         for (i, bucket) in self.buckets.iter().enumerate() {
-            if bucket.range.1 > node.id {
+            if bucket.contains_id_in_range(node.id) {
                 return i;
             }
         }
-        return 0;
+        0
     }
 
-    fn split_bucket(&mut self, index: usize) {
+    // Refuses to split once the table has max_buckets buckets, in which case the node that
+    // triggered the split is dropped like any other that doesn't fit.
+    fn split_bucket(&mut self, index: usize) -> bool {
+        if self.buckets.len() >= self.max_buckets {
+            return false;
+        }
         let (bucket1, bucket2) = self.buckets[index].split();
         self.buckets[index] = bucket1;
         self.buckets.insert(index + 1, bucket2);
+        debug_assert!(self.buckets_are_ordered());
+        true
+    }
+
+    // Buckets tile the whole id space in ascending order: each range starts right after the
+    // previous one ends. Splitting in place keeps this true, and lookups rely on it.
+    fn buckets_are_ordered(&self) -> bool {
+        self.buckets.first().is_some_and(|bucket| bucket.range.0 == NodeId::MIN)
+            && self.buckets.last().is_some_and(|bucket| bucket.range.1 == NodeId::MAX)
+            && self.buckets.windows(2).all(|pair| {
+                pair[0].range.1 < pair[1].range.0 && pair[0].range.1.plus_one() == pair[1].range.0
+            })
     }
 }
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+
+use {BencodeObject, Bencodeable};
+use err::KrpcError;
+use kademlia::{NodeId, Node};
+
+
+pub type InfoHash = [u8; 20];
+
+// BEP 51 caps the number of samples in a single response.
+const MAX_SAMPLES: usize = 20;
+
+
+// A KRPC response (`y` = `r`). The transaction id is exposed as-is for matching against
+// the outstanding query; the responder's id is checked to be a full 20-byte node id.
+#[derive(Debug)]
+pub struct Response {
+    pub transaction_id: Vec<u8>,
+    pub id: [u8; 20],
+    pub values: BTreeMap<String, BencodeObject>,
+    // The sender's client and version (`v`), if it sent one.
+    pub version: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn parse(bytes: &[u8]) -> Result<Response, KrpcError> {
+        Response::from_bencode(BencodeObject::parse(bytes)?)
+    }
+
+    pub fn from_bencode(obj: BencodeObject) -> Result<Response, KrpcError> {
+        let mut message = match obj {
+            BencodeObject::Dict(dict) => dict,
+            _ => return Err(KrpcError::MalformedMessage("message is not a dict")),
+        };
+        let transaction_id = match message.remove("t") {
+            Some(BencodeObject::Bytes(t)) => t,
+            _ => return Err(KrpcError::MalformedMessage("missing transaction id")),
+        };
+        match message.get("y") {
+            Some(BencodeObject::Bytes(y)) if y == b"r" => (),
+            _ => return Err(KrpcError::MalformedMessage("not a response")),
+        }
+        let values = match message.remove("r") {
+            Some(BencodeObject::Dict(r)) => r,
+            _ => return Err(KrpcError::MalformedMessage("missing response values")),
+        };
+        let id = match values.get("id") {
+            Some(BencodeObject::Bytes(id)) if id.len() == 20 => {
+                let mut array = [0; 20];
+                array.copy_from_slice(id);
+                array
+            },
+            _ => return Err(KrpcError::MalformedMessage("response id is not 20 bytes")),
+        };
+
+        Ok(Response { transaction_id, id, values, version: take_version(&mut message) })
+    }
+
+    pub fn to_bencode(&self) -> BencodeObject {
+        let mut message = BTreeMap::new();
+        message.insert("t".to_string(), self.transaction_id.clone().bencode());
+        message.insert("y".to_string(), "r".bencode());
+        message.insert("r".to_string(), self.values.clone().bencode());
+        if let Some(ref version) = self.version {
+            message.insert("v".to_string(), version.clone().bencode());
+        }
+        BencodeObject::Dict(message)
+    }
+}
+
+// A KRPC query (`y` = `q`). Methods we don't implement still parse, as Unknown, so the
+// server can answer them with a 204 error instead of dropping them.
+#[derive(Debug)]
+pub enum Query {
+    Ping { id: [u8; 20] },
+    FindNode { id: [u8; 20], target: [u8; 20] },
+    GetPeers { id: [u8; 20], info_hash: InfoHash },
+    AnnouncePeer { id: [u8; 20], info_hash: InfoHash, port: u16, implied_port: bool, token: Vec<u8> },
+    // BEP 51
+    SampleInfohashes { id: [u8; 20], target: [u8; 20] },
+    Unknown { method: String, args: BencodeObject },
+}
+
+#[derive(Debug)]
+pub struct QueryMessage {
+    pub transaction_id: Vec<u8>,
+    pub query: Query,
+    pub version: Option<Vec<u8>>,
+}
+
+impl QueryMessage {
+    pub fn parse(bytes: &[u8]) -> Result<QueryMessage, KrpcError> {
+        QueryMessage::from_bencode(BencodeObject::parse(bytes)?)
+    }
+
+    pub fn from_bencode(obj: BencodeObject) -> Result<QueryMessage, KrpcError> {
+        let mut message = match obj {
+            BencodeObject::Dict(dict) => dict,
+            _ => return Err(KrpcError::MalformedMessage("message is not a dict")),
+        };
+        let transaction_id = match message.remove("t") {
+            Some(BencodeObject::Bytes(t)) => t,
+            _ => return Err(KrpcError::MalformedMessage("missing transaction id")),
+        };
+        match message.get("y") {
+            Some(BencodeObject::Bytes(y)) if y == b"q" => (),
+            _ => return Err(KrpcError::MalformedMessage("not a query")),
+        }
+        let method = match message.remove("q") {
+            Some(BencodeObject::Bytes(q)) => String::from_utf8(q).map_err(|_| KrpcError::MalformedMessage("query method is not utf-8"))?,
+            _ => return Err(KrpcError::MalformedMessage("missing query method")),
+        };
+        let args = match message.remove("a") {
+            Some(BencodeObject::Dict(a)) => a,
+            _ => return Err(KrpcError::MalformedMessage("missing query arguments")),
+        };
+
+        let query = match &method[..] {
+            "ping" => Query::Ping { id: get_id(&args, "id")? },
+            "find_node" => Query::FindNode { id: get_id(&args, "id")?, target: get_id(&args, "target")? },
+            "get_peers" => Query::GetPeers { id: get_id(&args, "id")?, info_hash: get_id(&args, "info_hash")? },
+            "sample_infohashes" => Query::SampleInfohashes { id: get_id(&args, "id")?, target: get_id(&args, "target")? },
+            "announce_peer" => {
+                let port = match args.get("port") {
+                    Some(&BencodeObject::Integer(port)) if port >= 0 && port <= u16::MAX as i64 => port as u16,
+                    _ => return Err(KrpcError::MalformedMessage("announce port out of range")),
+                };
+                let token = match args.get("token") {
+                    Some(BencodeObject::Bytes(token)) => token.clone(),
+                    _ => return Err(KrpcError::MalformedMessage("missing announce token")),
+                };
+                let implied_port = match args.get("implied_port") {
+                    Some(&BencodeObject::Integer(implied_port)) => implied_port != 0,
+                    _ => false,
+                };
+                Query::AnnouncePeer { id: get_id(&args, "id")?, info_hash: get_id(&args, "info_hash")?, port, implied_port, token }
+            },
+            _ => Query::Unknown { method, args: BencodeObject::Dict(args) },
+        };
+
+        Ok(QueryMessage { transaction_id, query, version: take_version(&mut message) })
+    }
+
+    pub fn to_bencode(&self) -> BencodeObject {
+        let (method, args) = match self.query {
+            Query::Ping { id } => ("ping", bencode!["id" => id.to_vec()]),
+            Query::FindNode { id, target } => ("find_node", bencode!["id" => id.to_vec(), "target" => target.to_vec()]),
+            Query::GetPeers { id, info_hash } => ("get_peers", bencode!["id" => id.to_vec(), "info_hash" => info_hash.to_vec()]),
+            Query::AnnouncePeer { id, info_hash, port, implied_port, ref token } => ("announce_peer", bencode![
+                "id" => id.to_vec(),
+                "info_hash" => info_hash.to_vec(),
+                "port" => port as i64,
+                "implied_port" => implied_port as i64,
+                "token" => token.clone()
+            ]),
+            Query::SampleInfohashes { id, target } => ("sample_infohashes", bencode!["id" => id.to_vec(), "target" => target.to_vec()]),
+            Query::Unknown { ref method, ref args } => (&method[..], args.clone()),
+        };
+        let mut message = BTreeMap::new();
+        message.insert("t".to_string(), self.transaction_id.clone().bencode());
+        message.insert("y".to_string(), "q".bencode());
+        message.insert("q".to_string(), method.to_string().bencode());
+        message.insert("a".to_string(), args);
+        if let Some(ref version) = self.version {
+            message.insert("v".to_string(), version.clone().bencode());
+        }
+        BencodeObject::Dict(message)
+    }
+}
+
+// A `v` that isn't a byte string is ignored rather than rejecting the message.
+fn take_version(message: &mut BTreeMap<String, BencodeObject>) -> Option<Vec<u8>> {
+    match message.remove("v") {
+        Some(BencodeObject::Bytes(v)) => Some(v),
+        _ => None,
+    }
+}
+
+fn get_id(args: &BTreeMap<String, BencodeObject>, key: &str) -> Result<[u8; 20], KrpcError> {
+    match args.get(key) {
+        Some(BencodeObject::Bytes(id)) if id.len() == 20 => {
+            let mut array = [0; 20];
+            array.copy_from_slice(id);
+            Ok(array)
+        },
+        _ => Err(KrpcError::MalformedMessage("query argument is not 20 bytes")),
+    }
+}
+
+// KRPC error message (`y` = `e`). BEP 5 uses 204 for "method unknown".
+pub fn build_error(tx_id: &[u8], code: i64, message: &str) -> BencodeObject {
+    bencode![
+        "t" => tx_id.to_vec(),
+        "y" => "e",
+        "e" => bencode![code, message.to_string()]
+    ]
+}
+
+pub fn encode_compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    nodes.iter().flat_map(|node| node.to_compact().to_vec()).collect()
+}
+
+pub fn decode_compact_nodes(bytes: &[u8]) -> Option<Vec<Node>> {
+    if bytes.len() % 26 != 0 {
+        return None;
+    }
+    bytes.chunks(26).map(Node::from_compact).collect()
+}
+
+pub fn encode_compact_infohashes(hashes: &[InfoHash]) -> Vec<u8> {
+    hashes.iter().flat_map(|hash| hash.to_vec()).collect()
+}
+
+pub fn decode_compact_infohashes(bytes: &[u8]) -> Option<Vec<InfoHash>> {
+    if bytes.len() % 20 != 0 {
+        return None;
+    }
+    Some(bytes.chunks(20).map(|chunk| {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(chunk);
+        hash
+    }).collect())
+}
+
+// Typed view of a BEP 51 `sample_infohashes` response.
+#[derive(Debug)]
+pub struct SampleInfohashes {
+    pub id: [u8; 20],
+    pub interval: i64,
+    pub num: i64,
+    pub nodes: Vec<Node>,
+    pub samples: Vec<InfoHash>,
+}
+
+impl SampleInfohashes {
+    pub fn from_response(response: &Response) -> Result<SampleInfohashes, KrpcError> {
+        let r = &response.values;
+        let int = |key: &'static str| match r.get(key) {
+            Some(&BencodeObject::Integer(n)) if n >= 0 => Ok(n),
+            _ => Err(KrpcError::MalformedMessage("sample_infohashes interval or num is missing")),
+        };
+        let nodes = match r.get("nodes") {
+            Some(BencodeObject::Bytes(nodes)) => decode_compact_nodes(nodes)
+                .ok_or(KrpcError::MalformedMessage("nodes is not a multiple of 26 bytes"))?,
+            None => Vec::new(),
+            _ => return Err(KrpcError::MalformedMessage("nodes is not a byte string")),
+        };
+        let samples = match r.get("samples") {
+            Some(BencodeObject::Bytes(samples)) => decode_compact_infohashes(samples)
+                .ok_or(KrpcError::MalformedMessage("samples is not a multiple of 20 bytes"))?,
+            _ => return Err(KrpcError::MalformedMessage("missing samples")),
+        };
+        Ok(SampleInfohashes { id: response.id, interval: int("interval")?, num: int("num")?, nodes, samples })
+    }
+}
+
+// BEP 51 `sample_infohashes` response. `samples` is everything we store; `num` reports all
+// of them, while only the first MAX_SAMPLES go out in `samples`.
+pub fn build_sample_infohashes_response(own_id: NodeId, tx_id: &[u8], interval: i64, nodes: &[Node], samples: &[InfoHash]) -> BencodeObject {
+    let sent = &samples[..samples.len().min(MAX_SAMPLES)];
+    bencode![
+        "t" => tx_id.to_vec(),
+        "y" => "r",
+        "r" => bencode![
+            "id" => own_id,
+            "interval" => interval,
+            "nodes" => encode_compact_nodes(nodes),
+            "num" => samples.len() as i64,
+            "samples" => encode_compact_infohashes(sent)
+        ]
+    ]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use {BencodeObject, Bencodeable};
+    use err::KrpcError;
+    use kademlia::{NodeId, Node};
+    use krpc::{Response, QueryMessage, Query, SampleInfohashes, InfoHash, build_error, build_sample_infohashes_response, decode_compact_nodes, decode_compact_infohashes,
+               encode_compact_infohashes};
+
+    #[test]
+    fn test_parse_response() {
+        let response = Response::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(response.transaction_id, b"aa");
+        assert_eq!(&response.id, b"mnopqrstuvwxyz123456");
+        assert!(response.values.contains_key("id"));
+    }
+
+    #[test]
+    fn test_parse_response_short_id() {
+        match Response::parse(b"d1:rd2:id19:mnopqrstuvwxyz12345e1:t2:aa1:y1:re") {
+            Err(KrpcError::MalformedMessage(_)) => (),
+            other => panic!("expected MalformedMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_response_missing_transaction_id() {
+        match Response::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:y1:re") {
+            Err(KrpcError::MalformedMessage(_)) => (),
+            other => panic!("expected MalformedMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compact_infohashes() {
+        let hashes: Vec<InfoHash> = vec![[1; 20], [2; 20]];
+        let encoded = encode_compact_infohashes(&hashes);
+        assert_eq!(encoded.len(), 40);
+        assert_eq!(decode_compact_infohashes(&encoded).unwrap(), hashes);
+        assert!(decode_compact_infohashes(&encoded[1..]).is_none());
+    }
+
+    #[test]
+    fn test_build_sample_infohashes_response() {
+        let own_id = NodeId::from_bytes(b"abcdefghij0123456789");
+        let nodes = vec![
+            Node::from((NodeId::from_bytes(&[7; 20]), SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881))),
+            Node::from((NodeId::from_bytes(&[8; 20]), SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6882))),
+        ];
+        let samples: Vec<InfoHash> = (0..25).map(|i| [i as u8; 20]).collect();
+
+        let obj = build_sample_infohashes_response(own_id, b"aa", 21600, &nodes, &samples);
+        let response = Response::from_bencode(obj).unwrap();
+        assert_eq!(response.transaction_id, b"aa");
+        assert_eq!(&response.id, b"abcdefghij0123456789");
+
+        let r = BencodeObject::Dict(response.values);
+        assert_eq!(r.get_int_or("interval", 0), 21600);
+        assert_eq!(r.get_int_or("num", 0), 25);
+        let decoded_nodes = decode_compact_nodes(r.get_bytes_or("nodes", b"")).unwrap();
+        assert_eq!(decoded_nodes.iter().map(|node| node.id).collect::<Vec<_>>(), vec![nodes[0].id, nodes[1].id]);
+        assert_eq!(decoded_nodes[1].port, 6882);
+        assert_eq!(decode_compact_infohashes(r.get_bytes_or("samples", b"")).unwrap(), &samples[..20]);
+    }
+
+    #[test]
+    fn test_sample_infohashes_round_trip() {
+        let message = QueryMessage::parse(b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe").unwrap();
+        match message.query {
+            Query::SampleInfohashes { id, target } => {
+                assert_eq!(&id, b"abcdefghij0123456789");
+                assert_eq!(&target, b"mnopqrstuvwxyz123456");
+            },
+            ref other => panic!("expected SampleInfohashes, got {:?}", other),
+        }
+        let encoded = message.to_bencode().into_bytes();
+        assert_eq!(&encoded[..], &b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe"[..]);
+
+        let own_id = NodeId::from_bytes(b"abcdefghij0123456789");
+        let samples: Vec<InfoHash> = vec![[1; 20], [2; 20]];
+        let bytes = build_sample_infohashes_response(own_id, b"bb", 60, &[], &samples).into_bytes();
+        let sampled = SampleInfohashes::from_response(&Response::parse(&bytes).unwrap()).unwrap();
+        assert_eq!(&sampled.id, b"abcdefghij0123456789");
+        assert_eq!(sampled.interval, 60);
+        assert_eq!(sampled.num, 2);
+        assert!(sampled.nodes.is_empty());
+        assert_eq!(sampled.samples, samples);
+    }
+
+    #[test]
+    fn test_sample_infohashes_misaligned() {
+        let bytes = b"d1:rd2:id20:mnopqrstuvwxyz1234568:intervali60e3:numi2e7:samples21:aaaaaaaaaaaaaaaaaaaaae1:t2:aa1:y1:re";
+        match SampleInfohashes::from_response(&Response::parse(bytes).unwrap()) {
+            Err(KrpcError::MalformedMessage(_)) => (),
+            other => panic!("expected MalformedMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_version_round_trip() {
+        let response = Response::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:v4:UT011:y1:re").unwrap();
+        assert_eq!(response.version, Some(b"UT01".to_vec()));
+        let response = Response::from_bencode(response.to_bencode()).unwrap();
+        assert_eq!(response.version, Some(b"UT01".to_vec()));
+        assert_eq!(&response.id, b"mnopqrstuvwxyz123456");
+
+        let response = Response::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(response.version, None);
+        assert!(response.to_bencode().get("v").is_none());
+
+        let mut query = QueryMessage::parse(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(query.version, None);
+        assert!(query.to_bencode().get("v").is_none());
+        query.version = Some(b"LT12".to_vec());
+        let query = QueryMessage::from_bencode(query.to_bencode()).unwrap();
+        assert_eq!(query.version, Some(b"LT12".to_vec()));
+        assert_eq!(query.transaction_id, b"aa");
+        match query.query {
+            Query::Ping { id } => assert_eq!(&id, b"abcdefghij0123456789"),
+            other => panic!("expected Ping, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_find_node_query() {
+        let message = QueryMessage::parse(b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(message.transaction_id, b"aa");
+        match message.query {
+            Query::FindNode { id, target } => {
+                assert_eq!(&id, b"abcdefghij0123456789");
+                assert_eq!(&target, b"mnopqrstuvwxyz123456");
+            },
+            other => panic!("expected FindNode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_query() {
+        let message = QueryMessage::parse(b"d1:ad2:id20:abcdefghij01234567895:votesi4ee1:q4:vote1:t2:aa1:y1:qe").unwrap();
+        match message.query {
+            Query::Unknown { method, args } => {
+                assert_eq!(method, "vote");
+                assert_eq!(args.get_int_or("votes", 0), 4);
+                assert_eq!(args.get_bytes_or("id", b""), b"abcdefghij0123456789");
+            },
+            other => panic!("expected Unknown, got {:?}", other),
+        }
+
+        let reply = build_error(&message.transaction_id, 204, "Method Unknown");
+        assert_eq!(reply.get("e"), Some(&bencode![204, "Method Unknown"]));
+        assert_eq!(reply.get_bytes_or("t", b""), b"aa");
+        assert_eq!(reply.get_str_or("y", ""), "e");
+    }
+}
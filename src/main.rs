@@ -1,18 +1,59 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::str::{from_utf8, from_utf8_unchecked};
 use std::result::Result;
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::Ipv4Addr;
+use std::fmt;
+use std::convert::TryFrom;
+
+macro_rules! bencode (
+    { $($key:expr => $value:expr),+ } => {{
+        let mut map = BTreeMap::new();
+        $(
+            map.insert($key.to_string(), $value.bencode());
+        )+
+        BencodeObject::Dict(map)
+    }};
+    { $($x:expr),* } => {{
+        let mut vec = Vec::new();
+        $(
+            vec.push($x.bencode());
+        )*
+        BencodeObject::List(vec)
+    }};
+);
 
 mod err;
-use err::BencodeError;
+use err::{BencodeError, FileError};
 mod kademlia;
 use kademlia::{NodeId, Node};
+mod tokenizer;
+use tokenizer::{Tokenizer, Token};
+mod krpc;
+mod patch;
+mod torrent;
+mod hash;
+use hash::Hasher20;
+#[cfg(feature = "sha1")]
+use hash::Sha1;
+mod bootstrap;
+mod recent;
+mod bencode_ref;
+use bencode_ref::BencodeRef;
+mod stream;
+use stream::StreamParser;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod test_util;
 
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq)]
 enum BencodeObject {
     Integer(i64),
     Bytes(Vec<u8>),
@@ -20,19 +61,89 @@ enum BencodeObject {
     Dict(BTreeMap<String, BencodeObject>)
 }
 
+// Byte strings longer than this are shown as their length and a short prefix in Debug
+// output, so that e.g. a torrent's `pieces` doesn't flood the logs.
+const DEBUG_BYTES_LIMIT: usize = 64;
+const DEBUG_BYTES_PREFIX: usize = 16;
+
+const SUMMARY_BYTES_LIMIT: usize = 32;
+const SUMMARY_LIST_LIMIT: usize = 5;
+
+impl fmt::Debug for BencodeObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BencodeObject::Integer(ref i) => f.debug_tuple("Integer").field(i).finish(),
+            BencodeObject::Bytes(ref bytes) if bytes.len() > DEBUG_BYTES_LIMIT => {
+                write!(f, "Bytes(<{} bytes> {:?}..)", bytes.len(), &bytes[..DEBUG_BYTES_PREFIX])
+            },
+            BencodeObject::Bytes(ref bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            BencodeObject::List(ref list) => f.debug_tuple("List").field(list).finish(),
+            BencodeObject::Dict(ref dict) => f.debug_tuple("Dict").field(dict).finish(),
+        }
+    }
+}
+
 impl BencodeObject {
     fn parse<S: Into<Vec<u8>>>(_bytes: S) -> Result<BencodeObject, BencodeError> {
+        BencodeObject::parse_with_options(_bytes, &ParseOptions::default())
+    }
+
+    fn parse_with_options<S: Into<Vec<u8>>>(_bytes: S, options: &ParseOptions) -> Result<BencodeObject, BencodeError> {
         let bytes = _bytes.into();
-        let mut i = 0;
-        let len = bytes.len();
-        let bencode_object = _parse(&bytes, &mut i)?;
-        if i == len {
-            Ok(bencode_object)
-        } else {
-            Err(BencodeError::UnexpectedCharacter(i))
+        _parse_all(&bytes, false, options)
+    }
+
+    // Parses from a reader without holding the whole input in memory; see StreamParser.
+    fn from_reader<R: Read>(reader: R) -> Result<BencodeObject, BencodeError> {
+        StreamParser::new(reader, None).parse_all()
+    }
+
+    // As from_reader, but fails with InputTooLarge rather than read past `max_bytes`.
+    fn from_reader_with_limit<R: Read>(reader: R, max_bytes: usize) -> Result<BencodeObject, BencodeError> {
+        StreamParser::new(reader, Some(max_bytes)).parse_all()
+    }
+
+    // Zero-copy parse; see BencodeRef.
+    fn parse_ref(bytes: &[u8]) -> Result<BencodeRef<'_>, BencodeError> {
+        BencodeRef::parse(bytes)
+    }
+
+    // Parses and reports what the resulting object is made of, for tracking down inputs
+    // that blow up in memory.
+    fn parse_with_stats(bytes: &[u8]) -> Result<(BencodeObject, ParseStats), BencodeError> {
+        let obj = _parse_all(bytes, false, &ParseOptions::default())?;
+        let mut stats = ParseStats::default();
+        stats.record(&obj, 0);
+        Ok((obj, stats))
+    }
+
+    // Runs `on_bytes` over every byte string, dict keys included, as it is read, and stores
+    // what it returns instead. Rewritten keys must still be UTF-8.
+    fn parse_with_hook<F>(bytes: &[u8], mut on_bytes: F) -> Result<BencodeObject, BencodeError>
+        where F: FnMut(&[u8]) -> Cow<[u8]> {
+        _parse_all_with_hook(bytes, false, &ParseOptions::default(), &mut Some(&mut on_bytes))
+    }
+
+    // Parses input that is already known to be valid bencode. The one check it skips is UTF-8
+    // validation of dict keys; everything else is still checked. Meant for re-parsing bytes we
+    // produced ourselves.
+    //
+    // Safety: every dict key in `bytes` must be valid UTF-8; a non-UTF-8 key is undefined
+    // behavior. Any other malformed input panics.
+    unsafe fn parse_trusted(bytes: &[u8]) -> BencodeObject {
+        match _parse_all(bytes, true, &ParseOptions::default()) {
+            Ok(bencode_object) => bencode_object,
+            Err(err) => panic!("parse_trusted called on invalid bencode: {}", err),
         }
     }
 
+    // Safe stand-in for parse_trusted at call sites that can't vouch for their input. It
+    // always validates, so bad input is an error in every build rather than undefined
+    // behavior.
+    fn parse_trusted_checked(bytes: &[u8]) -> Result<BencodeObject, BencodeError> {
+        _parse_all(bytes, false, &ParseOptions::default())
+    }
+
     fn into_bytes(&self) -> Vec<u8> {
         match *self {
             BencodeObject::Integer(ref i) => format!("i{}e", i).into_bytes(),
@@ -52,6 +163,7 @@ impl BencodeObject {
             BencodeObject::Dict(ref dict) => {
                 let mut final_bytes = vec![b'd'];
                 for (key, value) in dict {
+                    final_bytes.extend(format!("{}:", key.len()).into_bytes());
                     final_bytes.extend(key.as_bytes());
                     final_bytes.extend(value.into_bytes());
                 }
@@ -60,6 +172,339 @@ impl BencodeObject {
             },
         }
     }
+
+    fn encode_to<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&self.into_bytes())
+    }
+
+    fn write_to_path(&self, path: &Path) -> Result<(), io::Error> {
+        let mut file = File::create(path)?;
+        self.encode_to(&mut file)?;
+        file.sync_all()
+    }
+
+    // Writes next to `path` and renames over it, so readers never see a partial file.
+    fn write_to_path_atomic(&self, path: &Path) -> Result<(), io::Error> {
+        let temp_path = temp_path(path);
+        let result = self.write_to_path(&temp_path).and_then(|()| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    // Dicts are always held sorted, so the encoding is already canonical: equal values hash
+    // equally regardless of the key order they were parsed from.
+    fn canonical_hash<H: Hasher20>(&self, hasher: H) -> [u8; 20] {
+        hash::hash20(hasher, &self.into_bytes())
+    }
+
+    #[cfg(feature = "sha1")]
+    fn canonical_sha1(&self) -> [u8; 20] {
+        self.canonical_hash(Sha1::new())
+    }
+
+    // Dict keys sorted by their raw UTF-8 bytes, i.e. the order they appear on the wire.
+    // Returns an empty vec for anything that isn't a dict.
+    fn keys_byte_order(&self) -> Vec<&String> {
+        match *self {
+            BencodeObject::Dict(ref dict) => {
+                let mut keys: Vec<&String> = dict.keys().collect();
+                keys.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+                keys
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&BencodeObject> {
+        match *self {
+            BencodeObject::Dict(ref dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    // Lookups for optional dict fields. A missing key, or a value of the wrong type,
+    // yields the default.
+    fn get_int_or(&self, key: &str, default: i64) -> i64 {
+        match self.get(key) {
+            Some(&BencodeObject::Integer(n)) => n,
+            _ => default,
+        }
+    }
+
+    fn get_bytes_or<'a>(&'a self, key: &str, default: &'a [u8]) -> &'a [u8] {
+        match self.get(key) {
+            Some(BencodeObject::Bytes(bytes)) => bytes,
+            _ => default,
+        }
+    }
+
+    fn get_str_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        match self.get(key) {
+            Some(BencodeObject::Bytes(bytes)) => from_utf8(bytes).unwrap_or(default),
+            _ => default,
+        }
+    }
+
+    // Typed views of a value, None for any other variant.
+    fn as_int(&self) -> Option<i64> {
+        match *self {
+            BencodeObject::Integer(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            BencodeObject::Bytes(ref bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn as_list(&self) -> Option<&[BencodeObject]> {
+        match *self {
+            BencodeObject::List(ref list) => Some(list),
+            _ => None,
+        }
+    }
+
+    fn as_list_mut(&mut self) -> Option<&mut Vec<BencodeObject>> {
+        match *self {
+            BencodeObject::List(ref mut list) => Some(list),
+            _ => None,
+        }
+    }
+
+    fn as_dict(&self) -> Option<&BTreeMap<String, BencodeObject>> {
+        match *self {
+            BencodeObject::Dict(ref dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<String, BencodeObject>> {
+        match *self {
+            BencodeObject::Dict(ref mut dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    // For fields that must not be negative (ports, lengths), so a hostile `piece length`
+    // of -1 is caught when it is read.
+    fn as_non_negative_i64(&self) -> Option<i64> {
+        match *self {
+            BencodeObject::Integer(n) if n >= 0 => Some(n),
+            _ => None,
+        }
+    }
+
+    // Compares a byte string without branching on its contents, for checking secrets such as
+    // get_peers tokens. Only the length is allowed to leak.
+    fn bytes_eq_ct(&self, other: &[u8]) -> bool {
+        match *self {
+            BencodeObject::Bytes(ref bytes) if bytes.len() == other.len() => {
+                bytes.iter().zip(other).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+            },
+            _ => false,
+        }
+    }
+
+    // Non-overlapping views into a byte string, the last one possibly short. None if this
+    // isn't a byte string or `chunk_size` is zero.
+    fn iter_bytes(&self, chunk_size: usize) -> Option<impl Iterator<Item = &[u8]>> {
+        match *self {
+            BencodeObject::Bytes(ref bytes) if chunk_size > 0 => Some(bytes.chunks(chunk_size)),
+            _ => None,
+        }
+    }
+
+    // Fixed-width records whose width is only known at runtime, e.g. 26-byte compact nodes.
+    // None if this isn't a byte string whose length is a multiple of `width`.
+    fn chunks(&self, width: usize) -> Option<impl Iterator<Item = &[u8]>> {
+        match *self {
+            BencodeObject::Bytes(ref bytes) if width > 0 && bytes.len() % width == 0 => Some(bytes.chunks_exact(width)),
+            _ => None,
+        }
+    }
+
+    // Fixed-size records, e.g. `iter_bytes_exact::<20>()` over `pieces`. None if this isn't a
+    // byte string whose length is a multiple of N.
+    fn iter_bytes_exact<const N: usize>(&self) -> Option<impl Iterator<Item = &[u8; N]>> {
+        self.chunks(N).map(|chunks| chunks.map(|chunk| <&[u8; N]>::try_from(chunk).unwrap()))
+    }
+
+    // One-line description of the value's shape, e.g. `<int 42>`, `<str "hello">`,
+    // `<bytes 5>` (for non-UTF-8), `<list [2 items]>`, `<dict {2 keys: "a", "b"}>`.
+    fn format_canonical(&self) -> String {
+        match *self {
+            BencodeObject::Integer(i) => format!("<int {}>", i),
+            BencodeObject::Bytes(ref bytes) => match from_utf8(bytes) {
+                Ok(s) => format!("<str {:?}>", s),
+                Err(_) => format!("<bytes {}>", bytes.len()),
+            },
+            BencodeObject::List(ref list) => format!("<list [{} items]>", list.len()),
+            BencodeObject::Dict(ref dict) => {
+                let keys: Vec<String> = dict.keys().map(|key| format!("{:?}", key)).collect();
+                format!("<dict {{{} keys: {}}}>", dict.len(), keys.join(", "))
+            },
+        }
+    }
+
+    // Recursive, JSON-ish rendering for error messages. Byte strings are cut after
+    // SUMMARY_BYTES_LIMIT bytes and lists after SUMMARY_LIST_LIMIT elements, marked by `...`.
+    // Non-UTF-8 byte strings are shown as hex.
+    fn summary(&self) -> String {
+        match *self {
+            BencodeObject::Integer(i) => i.to_string(),
+            BencodeObject::Bytes(ref bytes) => {
+                let (shown, ellipsis) = if bytes.len() > SUMMARY_BYTES_LIMIT {
+                    (&bytes[..SUMMARY_BYTES_LIMIT], "...")
+                } else {
+                    (&bytes[..], "")
+                };
+                match from_utf8(bytes) {
+                    Ok(s) => {
+                        let mut end = shown.len();
+                        while !s.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        format!("{:?}{}", &s[..end], ellipsis)
+                    },
+                    Err(_) => {
+                        let hex: Vec<String> = shown.iter().map(|b| format!("{:02x}", b)).collect();
+                        format!("0x{}{}", hex.concat(), ellipsis)
+                    },
+                }
+            },
+            BencodeObject::List(ref list) => {
+                let mut items: Vec<String> = list.iter().take(SUMMARY_LIST_LIMIT).map(|o| o.summary()).collect();
+                if list.len() > SUMMARY_LIST_LIMIT {
+                    items.push("...".to_string());
+                }
+                format!("[{}]", items.join(", "))
+            },
+            BencodeObject::Dict(ref dict) => {
+                let items: Vec<String> = dict.iter().map(|(key, value)| format!("{:?}: {}", key, value.summary())).collect();
+                format!("{{{}}}", items.join(", "))
+            },
+        }
+    }
+
+    // Structural equality that skips `ignore_keys` in dicts at every level, e.g. to compare
+    // torrents that differ only in `creation date` or `announce`.
+    fn eq_ignoring(&self, other: &BencodeObject, ignore_keys: &[&str]) -> bool {
+        match (self, other) {
+            (BencodeObject::List(a), BencodeObject::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignoring(b, ignore_keys))
+            },
+            (BencodeObject::Dict(a), BencodeObject::Dict(b)) => {
+                let a = a.iter().filter(|&(key, _)| !ignore_keys.contains(&&key[..]));
+                let mut b = b.iter().filter(|&(key, _)| !ignore_keys.contains(&&key[..]));
+                for (key_a, value_a) in a {
+                    match b.next() {
+                        Some((key_b, value_b)) if key_a == key_b && value_a.eq_ignoring(value_b, ignore_keys) => (),
+                        _ => return false,
+                    }
+                }
+                b.next().is_none()
+            },
+            (a, b) => a == b,
+        }
+    }
+
+    // Copy for logging with every byte string longer than `max_bytes_shown` replaced by a
+    // `<N bytes>` placeholder, so message structure shows without the payload.
+    fn redacted(&self, max_bytes_shown: usize) -> BencodeObject {
+        match *self {
+            BencodeObject::Integer(i) => BencodeObject::Integer(i),
+            BencodeObject::Bytes(ref bytes) => if bytes.len() > max_bytes_shown {
+                BencodeObject::Bytes(format!("<{} bytes>", bytes.len()).into_bytes())
+            } else {
+                BencodeObject::Bytes(bytes.clone())
+            },
+            BencodeObject::List(ref list) => BencodeObject::List(list.iter().map(|o| o.redacted(max_bytes_shown)).collect()),
+            BencodeObject::Dict(ref dict) => BencodeObject::Dict(dict.iter()
+                .map(|(key, value)| (key.clone(), value.redacted(max_bytes_shown)))
+                .collect()),
+        }
+    }
+
+    // Cheap structural check that `bytes` looks like a torrent: a dict whose `info` dict has
+    // `name`, `piece length` and `pieces`, with `pieces` a whole number of SHA-1 hashes.
+    // Nothing is copied, and scanning stops once `info` has been checked.
+    fn is_valid_torrent(bytes: &[u8]) -> bool {
+        _is_valid_torrent(&mut Tokenizer::new(bytes)).unwrap_or(false)
+    }
+
+    // Parses only the value under `key` in a top-level dict, skipping over the others without
+    // allocating. None if the key is missing or the input isn't a dict.
+    fn parse_value_at_key(bytes: &[u8], key: &str) -> Result<Option<BencodeObject>, BencodeError> {
+        match _raw_value_at_key(bytes, key)? {
+            Some(raw) => _parse_all(raw, false, &ParseOptions::default()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // The exact bytes of the `info` value, as hashed for the info-hash.
+    fn extract_raw_info_dict(bytes: &[u8]) -> Result<Option<&[u8]>, BencodeError> {
+        _raw_value_at_key(bytes, "info")
+    }
+}
+
+fn _raw_value_at_key<'a>(bytes: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, BencodeError> {
+    let mut tokenizer = Tokenizer::new(bytes);
+    match tokenizer.next() {
+        Some(Ok(Token::DictStart)) => (),
+        Some(Err(err)) => return Err(err),
+        _ => return Ok(None),
+    }
+    loop {
+        match tokenizer.next() {
+            Some(Ok(Token::Bytes(k))) if k == key.as_bytes() => return tokenizer.skip_value().map(Some),
+            Some(Ok(Token::Bytes(_))) => { tokenizer.skip_value()?; },
+            Some(Ok(_)) => return Ok(None),
+            Some(Err(err)) => return Err(err),
+            None => return Err(BencodeError::UnexpectedEndOfInput),
+        }
+    }
+}
+
+fn _is_valid_torrent(tokenizer: &mut Tokenizer) -> Result<bool, BencodeError> {
+    match tokenizer.next() {
+        Some(Ok(Token::DictStart)) => (),
+        _ => return Ok(false),
+    }
+    loop {
+        match tokenizer.next() {
+            Some(Ok(Token::Bytes(b"info"))) => return _is_valid_info(tokenizer),
+            Some(Ok(Token::Bytes(_))) => { tokenizer.skip_value()?; },
+            _ => return Ok(false),
+        }
+    }
+}
+
+fn _is_valid_info(tokenizer: &mut Tokenizer) -> Result<bool, BencodeError> {
+    match tokenizer.next() {
+        Some(Ok(Token::DictStart)) => (),
+        _ => return Ok(false),
+    }
+    let (mut has_name, mut has_piece_length, mut has_pieces) = (false, false, false);
+    loop {
+        match tokenizer.next() {
+            Some(Ok(Token::Bytes(b"pieces"))) => match tokenizer.next() {
+                Some(Ok(Token::Bytes(pieces))) if pieces.len() % 20 == 0 => has_pieces = true,
+                _ => return Ok(false),
+            },
+            Some(Ok(Token::Bytes(key))) => {
+                has_name |= key == b"name";
+                has_piece_length |= key == b"piece length";
+                tokenizer.skip_value()?;
+            },
+            Some(Ok(Token::DictEnd)) => return Ok(has_name && has_piece_length && has_pieces),
+            _ => return Ok(false),
+        }
+    }
 }
 
 trait Bencodeable {
@@ -90,9 +535,10 @@ impl Bencodeable for Vec<u8> {
     }
 }
 
-impl Bencodeable for BTreeMap<String, BencodeObject> {
+// Covers maps that already hold BencodeObject values too, since those bencode to themselves.
+impl<T: Bencodeable> Bencodeable for BTreeMap<String, T> {
     fn bencode(self) -> BencodeObject {
-        BencodeObject::Dict(self)
+        BencodeObject::Dict(self.into_iter().map(|(key, value)| (key, value.bencode())).collect())
     }
 }
 
@@ -108,51 +554,106 @@ impl Bencodeable for &'static str {
     }
 }
 
+// Lets the macro take references. This clones the whole value first, so prefer passing
+// ownership when the value isn't needed afterwards.
+impl<T: Clone + Bencodeable> Bencodeable for &T {
+    fn bencode(self) -> BencodeObject {
+        self.clone().bencode()
+    }
+}
 
-macro_rules! bencode (
-    { $($key:expr => $value:expr),+ } => {{
-        let mut map = BTreeMap::new();
-        $(
-            map.insert($key.to_string(), $value.bencode());
-        )+
-        BencodeObject::Dict(map)
-    }};
-    { $($x:expr),* } => {{
-        let mut vec = Vec::new();
-        $(
-            vec.push($x.bencode());
-        )*
-        BencodeObject::List(vec)
-    }};
-);
+// Limits for parsing untrusted input. The defaults impose none.
+#[derive(Clone, Debug, Default)]
+struct ParseOptions {
+    // Most elements allowed in any single list, or entries in any single dict.
+    max_collection_len: Option<usize>,
+    // Skip a UTF-8 BOM or ASCII whitespace before the value instead of failing with
+    // LeadingGarbage.
+    skip_leading_garbage: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ParseStats {
+    integers: usize,
+    byte_strings: usize,
+    lists: usize,
+    dicts: usize,
+    // Dict keys are not counted as byte strings.
+    total_bytes: usize,
+    largest_bytes: usize,
+    // Containers nested inside each other; a lone scalar has depth 0.
+    max_depth: usize,
+}
+
+impl ParseStats {
+    fn record(&mut self, obj: &BencodeObject, depth: usize) {
+        self.max_depth = self.max_depth.max(depth);
+        match *obj {
+            BencodeObject::Integer(_) => self.integers += 1,
+            BencodeObject::Bytes(ref bytes) => {
+                self.byte_strings += 1;
+                self.total_bytes += bytes.len();
+                self.largest_bytes = self.largest_bytes.max(bytes.len());
+            },
+            BencodeObject::List(ref list) => {
+                self.lists += 1;
+                self.max_depth = self.max_depth.max(depth + 1);
+                for o in list {
+                    self.record(o, depth + 1);
+                }
+            },
+            BencodeObject::Dict(ref dict) => {
+                self.dicts += 1;
+                self.max_depth = self.max_depth.max(depth + 1);
+                for o in dict.values() {
+                    self.record(o, depth + 1);
+                }
+            },
+        }
+    }
+}
+
+type BytesHook<'h> = &'h mut dyn FnMut(&[u8]) -> Cow<[u8]>;
 
-fn _parse(bytes: &[u8], i: &mut usize) -> Result<BencodeObject, BencodeError> {
+fn _parse_all(bytes: &[u8], trusted: bool, options: &ParseOptions) -> Result<BencodeObject, BencodeError> {
+    _parse_all_with_hook(bytes, trusted, options, &mut None)
+}
+
+fn _parse_all_with_hook(bytes: &[u8], trusted: bool, options: &ParseOptions, hook: &mut Option<BytesHook>) -> Result<BencodeObject, BencodeError> {
+    let mut i = _leading_garbage_len(bytes);
+    if i > 0 && !options.skip_leading_garbage {
+        return Err(BencodeError::LeadingGarbage(i));
+    }
+    let bencode_object = _parse(bytes, &mut i, trusted, options, hook)?;
+    if i == bytes.len() {
+        Ok(bencode_object)
+    } else {
+        Err(BencodeError::UnexpectedCharacter(i))
+    }
+}
+
+// Every successful call to _parse consumes at least one byte. The list and dict loops rely
+// on this to terminate, and check it rather than spin on crafted input if it's ever broken.
+fn _parse(bytes: &[u8], i: &mut usize, trusted: bool, options: &ParseOptions, hook: &mut Option<BytesHook>) -> Result<BencodeObject, BencodeError> {
     if *i == bytes.len() {
         return Err(BencodeError::UnexpectedEndOfInput)
     }
 
     match bytes[*i] {
-        b'i' => {
-            *i += 1;
-            let start = *i;
-            while *i < bytes.len() && ((bytes[*i] >= b'0' && bytes[*i] <= b'9') || bytes[*i] == b'-') {
-                *i += 1;
-            }
-            if *i == bytes.len() {
-                return Err(BencodeError::UnexpectedEndOfInput);
-            }
-            if bytes[*i] != b'e' {
-                return Err(BencodeError::UnexpectedCharacter(*i));
-            }
-            *i += 1;
-            let n = unsafe { from_utf8_unchecked(&bytes[start .. *i-1]) }.parse::<i64>()?;
-            return Ok(BencodeObject::Integer(n));
-        },
+        b'i' => Ok(BencodeObject::Integer(_parse_int(bytes, i)?)),
         b'l' => {
             *i += 1;
+            // No capacity hint: the remaining input says little about this list's length, and
+            // guessing high lets a run of empty lists in a big input reserve far more memory
+            // than the input itself. Doubling keeps the reallocations logarithmic anyway.
             let mut vec = Vec::new();
             while *i < bytes.len() && bytes[*i] != b'e' {
-                vec.push(_parse(&bytes, i)?);
+                _check_collection_len(vec.len(), options)?;
+                let element_start = *i;
+                vec.push(_parse(&bytes, i, trusted, options, hook)?);
+                if *i == element_start {
+                    return Err(BencodeError::UnexpectedCharacter(*i));
+                }
             }
             if *i == bytes.len() {
                 return Err(BencodeError::UnexpectedEndOfInput);
@@ -164,12 +665,31 @@ fn _parse(bytes: &[u8], i: &mut usize) -> Result<BencodeObject, BencodeError> {
         b'd' => {
             *i += 1;
             let mut map = BTreeMap::new();
+            // Order is checked on the keys as they appear in the input, before any hook
+            // rewrites them.
+            let mut last_raw_key: Option<&[u8]> = None;
             while *i < bytes.len() && bytes[*i] != b'e' {
-                let key = match _parse(&bytes, i)? {
-                    BencodeObject::Bytes(bytes) => from_utf8(&bytes)?.to_string(),
-                    _ => return Err(BencodeError::DictionaryKeyNotString)
+                _check_collection_len(map.len(), options)?;
+                let entry_start = *i;
+                if !bytes[*i].is_ascii_digit() {
+                    _parse(&bytes, i, trusted, options, hook)?;
+                    return Err(BencodeError::DictionaryKeyNotString);
+                }
+                let raw_key = _parse_bytes(bytes, i)?;
+                if last_raw_key.is_some_and(|last| raw_key <= last) {
+                    return Err(BencodeError::UnorderedDictKeys(entry_start));
+                }
+                last_raw_key = Some(raw_key);
+                let key = _apply_hook(raw_key, hook);
+                let key = if trusted {
+                    unsafe { String::from_utf8_unchecked(key) }
+                } else {
+                    from_utf8(&key)?.to_string()
                 };
-                let value = _parse(&bytes, i)?;
+                let value = _parse(&bytes, i, trusted, options, hook)?;
+                if *i == entry_start {
+                    return Err(BencodeError::UnexpectedCharacter(*i));
+                }
                 map.insert(key, value);
             }
             if *i == bytes.len() {
@@ -180,34 +700,107 @@ fn _parse(bytes: &[u8], i: &mut usize) -> Result<BencodeObject, BencodeError> {
             return Ok(BencodeObject::Dict(map));
         },
         b'0' ... b'9' => {
-            let start = *i;
-            while *i < bytes.len() && (bytes[*i] >= b'0' && bytes[*i] <= b'9') {
-                *i += 1;
-            }
-            if *i == bytes.len() {
-                return Err(BencodeError::UnexpectedEndOfInput);
-            }
-            if bytes[*i] != b':' {
-                return Err(BencodeError::UnexpectedCharacter(*i));
-            }
-            let n = unsafe { from_utf8_unchecked(&bytes[start .. *i]) }.parse::<usize>()?;
-            *i += 1;
-            let bytes = &bytes[*i .. *i+n];
-            *i += n;
-
-            return Ok(BencodeObject::Bytes(bytes.to_vec()));
+            let raw = _parse_bytes(bytes, i)?;
+            Ok(BencodeObject::Bytes(_apply_hook(raw, hook)))
         },
         _ => Err(BencodeError::UnexpectedCharacter(*i))
     }
 }
 
+fn _apply_hook(raw: &[u8], hook: &mut Option<BytesHook>) -> Vec<u8> {
+    match *hook {
+        Some(ref mut on_bytes) => on_bytes(raw).into_owned(),
+        None => raw.to_vec(),
+    }
+}
+
+// Length of a UTF-8 byte order mark and/or ASCII whitespace at the start of `bytes`, as left
+// by mangled captures. No bencode value starts with either.
+fn _leading_garbage_len(bytes: &[u8]) -> usize {
+    let bom = if bytes.starts_with(b"\xef\xbb\xbf") { 3 } else { 0 };
+    bom + bytes[bom..].iter().take_while(|b| b.is_ascii_whitespace()).count()
+}
+
+// Called before adding another element to a collection that already holds `len`.
+fn _check_collection_len(len: usize, options: &ParseOptions) -> Result<(), BencodeError> {
+    match options.max_collection_len {
+        Some(limit) if len >= limit => Err(BencodeError::CollectionTooLarge { len: len + 1, limit }),
+        _ => Ok(()),
+    }
+}
+
+// Parses an integer starting at the `i` marker, leaving `i` just past the closing `e`.
+fn _parse_int(bytes: &[u8], i: &mut usize) -> Result<i64, BencodeError> {
+    *i += 1;
+    let start = *i;
+    while *i < bytes.len() && ((bytes[*i] >= b'0' && bytes[*i] <= b'9') || bytes[*i] == b'-') {
+        *i += 1;
+    }
+    if *i == bytes.len() {
+        return Err(BencodeError::UnexpectedEndOfInput);
+    }
+    if bytes[*i] != b'e' {
+        return Err(BencodeError::UnexpectedCharacter(*i));
+    }
+    if *i == start {
+        return Err(BencodeError::UnexpectedCharacter(*i));
+    }
+    // The spec forbids `-0` and a sign with no digits, blamed on the sign, and leading
+    // zeros: `i0e` is fine, `i03e` is not.
+    let digits = if bytes[start] == b'-' { start + 1 } else { start };
+    if digits > start && (*i == digits || &bytes[digits .. *i] == b"0") {
+        return Err(BencodeError::UnexpectedCharacter(start));
+    }
+    if *i - digits > 1 && bytes[digits] == b'0' {
+        return Err(BencodeError::UnexpectedCharacter(digits));
+    }
+    *i += 1;
+    let n = unsafe { from_utf8_unchecked(&bytes[start .. *i-1]) }.parse::<i64>()?;
+    Ok(n)
+}
+
+// Parses a length-prefixed byte string, returning a view into `bytes`.
+fn _parse_bytes<'a>(bytes: &'a [u8], i: &mut usize) -> Result<&'a [u8], BencodeError> {
+    let start = *i;
+    while *i < bytes.len() && (bytes[*i] >= b'0' && bytes[*i] <= b'9') {
+        *i += 1;
+    }
+    if *i == bytes.len() {
+        return Err(BencodeError::UnexpectedEndOfInput);
+    }
+    if bytes[*i] != b':' {
+        return Err(BencodeError::UnexpectedCharacter(*i));
+    }
+    let n = unsafe { from_utf8_unchecked(&bytes[start .. *i]) }.parse::<usize>()?;
+    if n > isize::MAX as usize {
+        return Err(BencodeError::LengthOverflow(start));
+    }
+    *i += 1;
+    if n > bytes.len() - *i {
+        return Err(BencodeError::UnexpectedEndOfInput);
+    }
+    let bytes = &bytes[*i .. *i+n];
+    *i += n;
 
-fn file_to_bytes(path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    Ok(bytes)
+}
+
+
+fn file_to_bytes(path: &Path) -> Result<Vec<u8>, FileError> {
     File::open(path).and_then(|mut file| {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
         Ok(bytes)
-    })
+    }).map_err(|err| FileError { path: path.to_path_buf(), err })
+}
+
+// A new name next to `path` on every call, e.g. `table.dat.1234.0.tmp` (process id, then a
+// per-process counter), so concurrent writers never share a temp file.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(format!(".{}.{}.tmp", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
 }
 
 
@@ -231,9 +824,656 @@ fn main() {
     };
 
     let node = Node {
-        id: NodeId {data: [1, 2, 3, 4, 5]},
+        id: NodeId::from_bytes(b"abcdefghij0123456789"),
         ip_address: Ipv4Addr::new(127, 0, 0, 1),
         port: 1234
     };
     println!("{:?}", node);
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+    use std::{env, fs, process};
+    use std::path::Path;
+    use {BencodeObject, Bencodeable, ParseOptions, ParseStats, file_to_bytes};
+    use err::{BencodeError, ErrorContext};
+    use test_util::random_values;
+    #[cfg(feature = "sha1")]
+    use hash::{self, Sha1};
+
+    #[test]
+    fn test_keys_byte_order() {
+        let mut map = BTreeMap::new();
+        map.insert("\u{e9}t\u{e9}".to_string(), BencodeObject::Integer(1));
+        map.insert("z".to_string(), BencodeObject::Integer(2));
+        map.insert("\u{ff41}".to_string(), BencodeObject::Integer(3));
+        map.insert("a".to_string(), BencodeObject::Integer(4));
+        let dict = BencodeObject::Dict(map);
+
+        let keys = dict.keys_byte_order();
+        assert_eq!(keys, vec!["a", "z", "\u{e9}t\u{e9}", "\u{ff41}"]);
+        for pair in keys.windows(2) {
+            assert!(pair[0].as_bytes() < pair[1].as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_keys_byte_order_not_dict() {
+        assert!(BencodeObject::Integer(1).keys_byte_order().is_empty());
+    }
+
+    #[test]
+    fn test_get_or_default() {
+        let torrent = BencodeObject::parse("d13:creation datei1480000000e5:filesi3e4:name6:ubuntue").unwrap();
+        assert_eq!(torrent.get_int_or("private", 0), 0);
+        assert_eq!(torrent.get_int_or("creation date", 0), 1480000000);
+        assert_eq!(torrent.get_str_or("name", ""), "ubuntu");
+        assert_eq!(torrent.get_str_or("comment", "none"), "none");
+        assert_eq!(torrent.get_bytes_or("name", b""), b"ubuntu");
+        assert_eq!(torrent.get_bytes_or("files", b"x"), b"x");
+        assert_eq!(BencodeObject::Integer(1).get_int_or("private", 7), 7);
+    }
+
+    #[test]
+    fn test_parse_value_at_key() {
+        let torrent = b"d8:announce35:http://tracker.example.com/announce13:creation datei1480000000e\
+4:infod6:lengthi1048576e4:name10:ubuntu.iso12:piece lengthi262144e6:pieces80:\
+aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccddddddddddddddddddddee";
+        assert_eq!(BencodeObject::parse_value_at_key(torrent, "creation date").unwrap(), Some(BencodeObject::Integer(1480000000)));
+        assert_eq!(BencodeObject::parse_value_at_key(torrent, "comment").unwrap(), None);
+        let info = BencodeObject::parse_value_at_key(torrent, "info").unwrap().unwrap();
+        assert_eq!(info.get_str_or("name", ""), "ubuntu.iso");
+
+        let raw = BencodeObject::extract_raw_info_dict(torrent).unwrap().unwrap();
+        assert!(raw.starts_with(b"d6:lengthi1048576e"));
+        assert!(raw.ends_with(b"ddddde"));
+        assert_eq!(BencodeObject::parse(raw).unwrap(), info);
+
+        assert_eq!(BencodeObject::extract_raw_info_dict(b"li1ee").unwrap(), None);
+        assert!(BencodeObject::extract_raw_info_dict(b"d4:infod4:name").is_err());
+    }
+
+    #[test]
+    fn test_bencode_generic_map() {
+        let mut lengths: BTreeMap<String, i64> = BTreeMap::new();
+        lengths.insert("a.flac".to_string(), 1000);
+        lengths.insert("b.flac".to_string(), 2500);
+        assert_eq!(lengths.bencode(), bencode!["a.flac" => 1000, "b.flac" => 2500]);
+
+        let mut nested: BTreeMap<String, BencodeObject> = BTreeMap::new();
+        nested.insert("list".to_string(), bencode![1, 2]);
+        assert_eq!(bencode!["info" => nested], bencode!["info" => bencode!["list" => bencode![1, 2]]]);
+    }
+
+    #[test]
+    fn test_bencode_references() {
+        let name = String::from("ubuntu");
+        let length: i64 = 5;
+        let info = bencode![&name, &length, &bencode![&name]];
+        assert_eq!(info, bencode!["ubuntu", 5, bencode!["ubuntu"]]);
+        assert_eq!(name, "ubuntu");
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn test_canonical_sha1() {
+        let a = bencode!["b" => 1, "a" => bencode!["y" => 0, "x" => 0]];
+        let b = BencodeObject::parse("d1:ad1:xi0e1:yi0ee1:bi1ee").unwrap();
+        assert_eq!(a.canonical_sha1(), b.canonical_sha1());
+        assert_ne!(a.canonical_sha1(), BencodeObject::parse("d1:ad1:xi0e1:yi0ee1:bi2ee").unwrap().canonical_sha1());
+
+        let list = bencode![1, "two"];
+        assert_eq!(list.canonical_sha1(), hash::hash20(Sha1::new(), b"li1e3:twoe"));
+    }
+
+    #[test]
+    fn test_parse_leading_garbage() {
+        match BencodeObject::parse(&b"\xef\xbb\xbfd1:ai1ee"[..]) {
+            Err(BencodeError::LeadingGarbage(3)) => (),
+            other => panic!("expected LeadingGarbage, got {:?}", other),
+        }
+        match BencodeObject::parse(" \r\ni1e") {
+            Err(BencodeError::LeadingGarbage(3)) => (),
+            other => panic!("expected LeadingGarbage, got {:?}", other),
+        }
+
+        let lenient = ParseOptions { skip_leading_garbage: true, ..ParseOptions::default() };
+        assert_eq!(BencodeObject::parse_with_options(&b"\xef\xbb\xbfd1:ai1ee"[..], &lenient).unwrap(), bencode!["a" => 1]);
+        assert_eq!(BencodeObject::parse_with_options("  i1e", &lenient).unwrap(), BencodeObject::Integer(1));
+        match BencodeObject::parse_with_options("  x", &lenient) {
+            Err(BencodeError::UnexpectedCharacter(2)) => (),
+            other => panic!("expected UnexpectedCharacter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_with_stats() {
+        let (obj, stats) = BencodeObject::parse_with_stats(b"d4:infod6:pieces10:0123456789e4:listlli1eei2e3:abcee").unwrap();
+        assert_eq!(obj.get_bytes_or("missing", b"x"), b"x");
+        assert_eq!(stats, ParseStats {
+            integers: 2,
+            byte_strings: 2,
+            lists: 2,
+            dicts: 2,
+            total_bytes: 13,
+            largest_bytes: 10,
+            max_depth: 3,
+        });
+
+        assert_eq!(BencodeObject::parse_with_stats(b"i5e").unwrap().1.max_depth, 0);
+        assert_eq!(BencodeObject::parse_with_stats(b"le").unwrap().1.max_depth, 1);
+        assert!(BencodeObject::parse_with_stats(b"l").is_err());
+    }
+
+    #[test]
+    fn test_parse_with_hook() {
+        let obj = BencodeObject::parse_with_hook(b"d4:name6:ubuntu4:tagsl3:iso3:x86ee", |bytes| {
+            if bytes == b"name" {
+                Cow::Borrowed(bytes)
+            } else {
+                Cow::Owned(bytes.to_ascii_uppercase())
+            }
+        }).unwrap();
+        assert_eq!(obj, bencode!["name" => "UBUNTU", "TAGS" => bencode!["ISO", "X86"]]);
+
+        let mut seen = 0;
+        let obj = BencodeObject::parse_with_hook(b"l1:a1:bi3ee", |bytes| {
+            seen += 1;
+            Cow::Borrowed(bytes)
+        }).unwrap();
+        assert_eq!(obj, bencode!["a", "b", 3]);
+        assert_eq!(seen, 2);
+
+        // Order is checked before the hook runs, so a hook may rewrite sorted keys into an
+        // order of its own, but can't make unsorted input acceptable.
+        fn reverse(bytes: &[u8]) -> Cow<'_, [u8]> {
+            match bytes {
+                b"a" => Cow::Borrowed(b"z"),
+                b"z" => Cow::Borrowed(b"a"),
+                other => Cow::Borrowed(other),
+            }
+        }
+        assert_eq!(BencodeObject::parse_with_hook(b"d1:ai1e1:zi2ee", reverse).unwrap(), bencode!["z" => 1, "a" => 2]);
+        match BencodeObject::parse_with_hook(b"d1:zi1e1:ai2ee", reverse) {
+            Err(BencodeError::UnorderedDictKeys(7)) => (),
+            other => panic!("expected UnorderedDictKeys(7), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_eq_ignoring() {
+        let a = bencode![
+            "announce" => "udp://a",
+            "creation date" => 1476367200,
+            "info" => bencode!["name" => "album", "files" => bencode![bencode!["length" => 1, "creation date" => 5]]]
+        ];
+        let b = bencode![
+            "announce" => "udp://a",
+            "creation date" => 1500000000,
+            "info" => bencode!["name" => "album", "files" => bencode![bencode!["length" => 1]]]
+        ];
+        assert!(a != b);
+        assert!(a.eq_ignoring(&b, &["creation date"]));
+        assert!(!a.eq_ignoring(&b, &["announce"]));
+
+        let c = bencode!["announce" => "udp://b", "info" => bencode!["name" => "album", "files" => bencode![bencode!["length" => 2]]]];
+        assert!(!a.eq_ignoring(&c, &["creation date", "announce"]));
+        assert!(BencodeObject::Integer(1).eq_ignoring(&BencodeObject::Integer(1), &[]));
+    }
+
+    #[test]
+    fn test_redacted() {
+        let message = bencode![
+            "t" => "aa",
+            "r" => bencode!["id" => vec![0u8; 20], "values" => bencode![vec![1u8; 6], "short"]]
+        ];
+        assert_eq!(message.redacted(5), bencode![
+            "t" => "aa",
+            "r" => bencode!["id" => "<20 bytes>", "values" => bencode!["<6 bytes>", "short"]]
+        ]);
+        assert_eq!(message.redacted(20).get("r").unwrap().get_bytes_or("id", b""), &[0u8; 20][..]);
+    }
+
+    #[test]
+    fn test_parse_max_collection_len() {
+        let options = ParseOptions { max_collection_len: Some(3), ..ParseOptions::default() };
+        assert_eq!(BencodeObject::parse_with_options("li1ei2ei3ee", &options).unwrap(), bencode![1, 2, 3]);
+        assert!(BencodeObject::parse_with_options("d1:ai1e1:bi2e1:ci3ee", &options).is_ok());
+        assert!(BencodeObject::parse_with_options("llei1eli1ei2ei3eee", &options).is_ok());
+        match BencodeObject::parse_with_options("li1ei2ei3ei4ee", &options) {
+            Err(BencodeError::CollectionTooLarge { len: 4, limit: 3 }) => (),
+            other => panic!("expected CollectionTooLarge, got {:?}", other),
+        }
+        match BencodeObject::parse_with_options("ld1:ai1e1:bi2e1:ci3e1:di4eee", &options) {
+            Err(BencodeError::CollectionTooLarge { len: 4, limit: 3 }) => (),
+            other => panic!("expected CollectionTooLarge, got {:?}", other),
+        }
+        assert!(BencodeObject::parse("li1ei2ei3ei4ee").is_ok());
+    }
+
+    #[test]
+    fn test_as_non_negative_i64() {
+        assert_eq!(BencodeObject::Integer(262144).as_non_negative_i64(), Some(262144));
+        assert_eq!(BencodeObject::Integer(0).as_non_negative_i64(), Some(0));
+        assert_eq!(BencodeObject::Integer(-1).as_non_negative_i64(), None);
+        assert_eq!(BencodeObject::Bytes(b"5".to_vec()).as_non_negative_i64(), None);
+    }
+
+    #[test]
+    fn test_bytes_eq_ct() {
+        let token = BencodeObject::Bytes(b"secret token".to_vec());
+        assert!(token.bytes_eq_ct(b"secret token"));
+        assert!(!token.bytes_eq_ct(b"secret tokem"));
+        assert!(!token.bytes_eq_ct(b"secret"));
+        assert!(!token.bytes_eq_ct(b""));
+        assert!(BencodeObject::Bytes(vec![]).bytes_eq_ct(b""));
+        assert!(!BencodeObject::Integer(0).bytes_eq_ct(b""));
+    }
+
+    #[test]
+    fn test_parse_length_overflow() {
+        match BencodeObject::parse("9223372036854775808:asdf") {
+            Err(BencodeError::LengthOverflow(0)) => (),
+            other => panic!("expected LengthOverflow, got {:?}", other),
+        }
+        match BencodeObject::parse("l18446744073709551615:e") {
+            Err(BencodeError::LengthOverflow(1)) => (),
+            other => panic!("expected LengthOverflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_unordered_dict_keys() {
+        match BencodeObject::parse("d1:b1:x1:a1:ye") {
+            Err(BencodeError::UnorderedDictKeys(7)) => (),
+            other => panic!("expected UnorderedDictKeys, got {:?}", other),
+        }
+        assert_eq!(BencodeObject::parse("d1:a1:x1:b1:ye").unwrap(), bencode!["a" => "x", "b" => "y"]);
+        // Raw byte order, so "B" sorts before "a".
+        assert!(BencodeObject::parse("d1:B1:x1:a1:ye").is_ok());
+    }
+
+    #[test]
+    fn test_parse_trusted() {
+        let bytes = b"d5:filesli1ei2ee4:name6:ubuntue";
+        let trusted = unsafe { BencodeObject::parse_trusted(bytes) };
+        assert_eq!(trusted.get_str_or("name", ""), "ubuntu");
+        assert_eq!(format!("{:?}", trusted), format!("{:?}", BencodeObject::parse(&bytes[..]).unwrap()));
+
+        let checked = BencodeObject::parse_trusted_checked(bytes).unwrap();
+        assert_eq!(format!("{:?}", checked), format!("{:?}", trusted));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_trusted_invalid() {
+        unsafe { BencodeObject::parse_trusted(b"l4:asdf") };
+    }
+
+    #[test]
+    fn test_parse_trusted_checked_invalid_key() {
+        match BencodeObject::parse_trusted_checked(b"d2:\xff\xfei1ee") {
+            Err(BencodeError::Utf8(_)) => (),
+            other => panic!("expected Utf8, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_empty_elements_make_progress() {
+        match BencodeObject::parse("l0:0:lel0:ee") {
+            Ok(BencodeObject::List(ref list)) => assert_eq!(list.len(), 4),
+            other => panic!("expected a list, got {:?}", other),
+        }
+        match BencodeObject::parse("d0:0:1:ad0:leee") {
+            Ok(BencodeObject::Dict(ref dict)) => assert_eq!(dict.len(), 2),
+            other => panic!("expected a dict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_list_with_garbage_element() {
+        match BencodeObject::parse("l0:xe") {
+            Err(BencodeError::UnexpectedCharacter(3)) => (),
+            other => panic!("expected UnexpectedCharacter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_to_bytes_missing_file() {
+        let path = Path::new("/nonexistent/dir/file.torrent");
+        let err = file_to_bytes(path).unwrap_err();
+        assert_eq!(err.path, path);
+        let message = format!("{}", err);
+        assert!(message.starts_with("Failed to read '/nonexistent/dir/file.torrent': "), "{}", message);
+
+        let err = BencodeError::from(err);
+        assert!(format!("{}", err).contains("/nonexistent/dir/file.torrent"));
+    }
+
+    #[test]
+    fn test_is_valid_torrent() {
+        let torrent = b"d8:announce9:localhost4:infod6:lengthi10e4:name3:foo12:piece lengthi16384e\
+6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        assert!(BencodeObject::is_valid_torrent(torrent));
+
+        assert!(!BencodeObject::is_valid_torrent(b"li1ei2ee"));
+        assert!(!BencodeObject::is_valid_torrent(b"d8:announce9:localhoste"));
+        assert!(!BencodeObject::is_valid_torrent(b"d4:infoli1eee"));
+        assert!(!BencodeObject::is_valid_torrent(b"d4:infod4:name3:foo12:piece lengthi1eee"));
+        assert!(!BencodeObject::is_valid_torrent(b"d4:infod4:name3:foo12:piece lengthi1e6:pieces3:abcee"));
+        assert!(!BencodeObject::is_valid_torrent(b"d4:infod4:name3:foo12:piece lengthi1e6:pieces"));
+    }
+
+    #[test]
+    fn test_debug_truncates_large_bytes() {
+        let pieces = BencodeObject::Bytes(vec![0xab; 100000]);
+        let debug = format!("{:?}", bencode!["pieces" => pieces, "length" => 5]);
+        assert!(debug.len() < 200, "{}", debug);
+        assert!(debug.contains("Bytes(<100000 bytes> [171, 171,"), "{}", debug);
+        assert!(debug.contains("\"length\": Integer(5)"), "{}", debug);
+
+        assert_eq!(format!("{:?}", bencode![1, "ab"]), "List([Integer(1), Bytes([97, 98])])");
+    }
+
+    #[test]
+    fn test_format_canonical() {
+        assert_eq!(BencodeObject::Integer(42).format_canonical(), "<int 42>");
+        assert_eq!("hello".bencode().format_canonical(), "<str \"hello\">");
+        assert_eq!(vec![0xffu8, 0xfe, 0, 1, 2].bencode().format_canonical(), "<bytes 5>");
+        assert_eq!(bencode![1, 2].format_canonical(), "<list [2 items]>");
+        assert_eq!(bencode!["c" => 1, "a" => 2, "b" => 3].format_canonical(), "<dict {3 keys: \"a\", \"b\", \"c\"}>");
+    }
+
+    #[test]
+    fn test_summary() {
+        let torrent = bencode![
+            "announce" => "http://tracker.example.com:6969/announce",
+            "announce-list" => bencode![
+                bencode!["udp://a"], bencode!["udp://b"], bencode!["udp://c"],
+                bencode!["udp://d"], bencode!["udp://e"], bencode!["udp://f"]
+            ],
+            "info" => bencode![
+                "name" => "ubuntu-16.10-desktop-amd64.iso",
+                "piece length" => 524288,
+                "pieces" => vec![0xffu8; 60]
+            ]
+        ];
+        assert_eq!(torrent.summary(), format!(
+            "{{\"announce\": \"http://tracker.example.com:6969/\"..., \
+\"announce-list\": [[\"udp://a\"], [\"udp://b\"], [\"udp://c\"], [\"udp://d\"], [\"udp://e\"], ...], \
+\"info\": {{\"name\": \"ubuntu-16.10-desktop-amd64.iso\", \"piece length\": 524288, \"pieces\": 0x{}...}}}}",
+            "ff".repeat(32)));
+    }
+
+    #[test]
+    fn test_summary_truncates_on_char_boundary() {
+        let s = format!("{}\u{e9}", "a".repeat(31));
+        assert_eq!(s.bencode().summary(), format!("\"{}\"...", "a".repeat(31)));
+        // A genuine replacement character is kept.
+        assert_eq!("ab\u{fffd}".bencode().summary(), "\"ab\u{fffd}\"");
+        let s = format!("{}\u{fffd}b", "a".repeat(29));
+        assert_eq!(s.bencode().summary(), format!("\"{}\u{fffd}\"...", "a".repeat(29)));
+    }
+
+    #[test]
+    fn test_error_context() {
+        let input = b"d8:announce35:http://tracker.example.com/announce4:infod4:name3:fooxe";
+        let err = BencodeObject::parse(&input[..]).unwrap_err();
+        let context = err.context(input).unwrap();
+        assert_eq!(context, ErrorContext {
+            offset: 67,
+            before: "infod4:name3:foo".to_string(),
+            after: "xe".to_string(),
+        });
+        assert_eq!(format!("{}", context), "byte 67 (near `infod4:name3:foo` <here> `xe`)");
+
+        assert!(BencodeError::UnexpectedEndOfInput.context(input).is_none());
+
+        let input = b"d4:name3:foo6:lengthi5ee";
+        let err = BencodeObject::parse(&input[..]).unwrap_err();
+        assert_eq!(err.position(), Some(12));
+        assert_eq!(err.context(input).unwrap().after, "6:lengthi5ee");
+        assert_eq!(BencodeError::UnexpectedCharacter(0).context(b"").unwrap().after, "");
+    }
+
+    fn random_object(random: &mut dyn Iterator<Item = u64>, depth: u32) -> BencodeObject {
+        let choice = random.next().unwrap();
+        match choice % if depth == 0 { 2 } else { 4 } {
+            0 => BencodeObject::Integer(random.next().unwrap() as i64 >> (choice % 64)),
+            1 => {
+                let len = (random.next().unwrap() % 40) as usize;
+                BencodeObject::Bytes((0..len).map(|_| random.next().unwrap() as u8).collect())
+            },
+            2 => {
+                let len = (random.next().unwrap() % 5) as usize;
+                BencodeObject::List((0..len).map(|_| random_object(random, depth - 1)).collect())
+            },
+            _ => {
+                let len = (random.next().unwrap() % 5) as usize;
+                BencodeObject::Dict((0..len).map(|_| (random_key(random), random_object(random, depth - 1))).collect())
+            },
+        }
+    }
+
+    // Up to 5 chars mixing 1-, 2-, 3- and 4-byte UTF-8 encodings, so key order and length
+    // prefixes are exercised on more than ASCII.
+    fn random_key(random: &mut dyn Iterator<Item = u64>) -> String {
+        let len = random.next().unwrap() % 6;
+        (0..len).map(|_| {
+            let n = random.next().unwrap();
+            let code = match n % 4 {
+                0 => (n >> 8) % 0x80,
+                1 => 0x80 + (n >> 8) % 0x780,
+                2 => 0xe000 + (n >> 8) % 0x1000,
+                _ => 0x10000 + (n >> 8) % 0x1000,
+            };
+            std::char::from_u32(code as u32).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn test_parse_int_leading_zeros() {
+        assert_eq!(BencodeObject::parse("i0e").unwrap(), BencodeObject::Integer(0));
+        assert_eq!(BencodeObject::parse("i10e").unwrap(), BencodeObject::Integer(10));
+        for &(input, position) in [("i00e", 1), ("i01e", 1), ("i007e", 1), ("i-01e", 2), ("li1ei02ee", 5)].iter() {
+            match BencodeObject::parse(input) {
+                Err(BencodeError::UnexpectedCharacter(p)) if p == position => (),
+                other => panic!("expected UnexpectedCharacter({}) for {}, got {:?}", position, input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_int_negative_zero() {
+        assert_eq!(BencodeObject::parse("i-123e").unwrap(), BencodeObject::Integer(-123));
+        for input in ["i-0e", "i-e", "li1ei-0ee"].iter() {
+            let position = input.find('-').unwrap();
+            match BencodeObject::parse(*input) {
+                Err(BencodeError::UnexpectedCharacter(p)) if p == position => (),
+                other => panic!("expected UnexpectedCharacter({}) for {}, got {:?}", position, input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_int_empty() {
+        match BencodeObject::parse("ie") {
+            Err(err @ BencodeError::UnexpectedCharacter(1)) => assert!(err.to_string().contains("position 1")),
+            other => panic!("expected UnexpectedCharacter(1), got {:?}", other),
+        }
+        match BencodeObject::parse("li1eiee") {
+            Err(BencodeError::UnexpectedCharacter(5)) => (),
+            other => panic!("expected UnexpectedCharacter(5), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_dicts() {
+        let objects = vec![
+            bencode![1 => 1],
+            BencodeObject::Dict(BTreeMap::new()),
+            bencode!["empty" => BencodeObject::Dict(BTreeMap::new()), "list" => BencodeObject::List(vec![])],
+            bencode!["info" => bencode!["files" => bencode![bencode!["length" => 5, "path" => bencode!["a"]]], "name" => "x"]],
+            bencode!["a1" => "b2", "10:" => 3, "i5e" => "i5e", "-1" => -1],
+        ];
+        for obj in objects {
+            assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj, "{:?}", obj);
+        }
+        assert_eq!(bencode!["cow" => "moo", "spam" => "eggs"].into_bytes(), b"d3:cow3:moo4:spam4:eggse".to_vec());
+    }
+
+    #[test]
+    fn test_dict_key_length_prefix() {
+        let obj = bencode!["" => 1];
+        assert_eq!(obj.into_bytes(), b"d0:i1ee".to_vec());
+        assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj);
+
+        // The prefix counts UTF-8 bytes, not chars.
+        let obj = BencodeObject::parse("d5:\u{e9}t\u{e9}i1e2:\u{3c0}i2ee").unwrap();
+        assert_eq!(obj.into_bytes(), "d5:\u{e9}t\u{e9}i1e2:\u{3c0}i2ee".as_bytes().to_vec());
+        assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj);
+    }
+
+    #[test]
+    fn test_round_trip_integers() {
+        let edges = [0, 1, -1, 9, 10, -10, i64::MAX, i64::MIN, i64::MAX - 1, i64::MIN + 1];
+        for &n in edges.iter().chain(random_values(1).take(1000).map(|n| n as i64).collect::<Vec<_>>().iter()) {
+            let obj = BencodeObject::Integer(n);
+            assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj, "integer {}", n);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_bytes_and_lists() {
+        for len in [0, 1, 9, 10, 255, 256, 1000].iter() {
+            let obj = BencodeObject::Bytes(random_values(*len as u64 + 1).take(*len).map(|n| n as u8).collect());
+            assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj);
+        }
+
+        let mut random = random_values(42);
+        let mut non_ascii_dicts = 0;
+        for _ in 0..200 {
+            let obj = random_object(&mut random, 4);
+            assert_eq!(BencodeObject::parse(obj.into_bytes()).unwrap(), obj, "{:?}", obj);
+            if let BencodeObject::Dict(ref dict) = obj {
+                non_ascii_dicts += dict.keys().any(|key| !key.is_ascii()) as usize;
+            }
+        }
+        assert!(non_ascii_dicts > 0);
+    }
+
+    #[test]
+    fn test_parse_large_list() {
+        let mut bytes = b"l".to_vec();
+        for n in 0..10000 {
+            bytes.extend(format!("i{}e", n).into_bytes());
+        }
+        bytes.push(b'e');
+
+        match BencodeObject::parse(bytes).unwrap() {
+            BencodeObject::List(list) => {
+                assert_eq!(list.len(), 10000);
+                for (n, element) in list.iter().enumerate() {
+                    match *element {
+                        BencodeObject::Integer(value) => assert_eq!(value, n as i64),
+                        ref other => panic!("expected an integer, got {:?}", other),
+                    }
+                }
+            },
+            other => panic!("expected a list, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_iter_bytes_pieces() {
+        let mut pieces = vec![0xaa; 20];
+        pieces.extend(vec![0xbb; 20]);
+        pieces.extend(vec![0xcc; 20]);
+        let pieces = BencodeObject::Bytes(pieces);
+
+        let chunks: Vec<&[u8]> = pieces.iter_bytes(20).unwrap().collect();
+        assert_eq!(chunks, vec![&[0xaa; 20][..], &[0xbb; 20][..], &[0xcc; 20][..]]);
+        assert_eq!(pieces.iter_bytes(25).unwrap().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![25, 25, 10]);
+
+        let hashes: Vec<&[u8; 20]> = pieces.iter_bytes_exact::<20>().unwrap().collect();
+        assert_eq!(hashes, vec![&[0xaa; 20], &[0xbb; 20], &[0xcc; 20]]);
+
+        assert!(pieces.iter_bytes_exact::<25>().is_none());
+        assert!(pieces.iter_bytes(0).is_none());
+        assert!(BencodeObject::Integer(1).iter_bytes(20).is_none());
+        assert!(BencodeObject::Integer(1).iter_bytes_exact::<20>().is_none());
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let mut torrent = bencode![
+            "info" => bencode!["length" => 5, "name" => "foo"],
+            "url-list" => bencode!["http://a"]
+        ];
+        let length = torrent.get("info").and_then(|info| info.get("length")).and_then(BencodeObject::as_int);
+        assert_eq!(length, Some(5));
+        assert_eq!(torrent.get("info").and_then(|info| info.get("name")).and_then(BencodeObject::as_bytes), Some(&b"foo"[..]));
+        assert_eq!(torrent.get("url-list").and_then(BencodeObject::as_list).map(|urls| urls.len()), Some(1));
+        assert_eq!(torrent.as_dict().map(|dict| dict.len()), Some(2));
+
+        assert_eq!(BencodeObject::Bytes(b"5".to_vec()).as_int(), None);
+        assert_eq!(BencodeObject::Integer(5).as_bytes(), None);
+        assert!(torrent.as_list().is_none());
+        assert!(BencodeObject::List(vec![]).as_dict().is_none());
+
+        torrent.as_dict_mut().unwrap().remove("url-list");
+        torrent.as_dict_mut().unwrap().get_mut("info").and_then(BencodeObject::as_dict_mut).unwrap().remove("name");
+        assert_eq!(torrent, bencode!["info" => bencode!["length" => 5]]);
+        assert!(torrent.as_list_mut().is_none());
+
+        let mut list = BencodeObject::List(vec![]);
+        list.as_list_mut().unwrap().push(BencodeObject::Integer(1));
+        assert_eq!(list.as_list(), Some(&[BencodeObject::Integer(1)][..]));
+        assert!(list.as_dict_mut().is_none());
+    }
+
+    #[test]
+    fn test_chunks() {
+        let field = BencodeObject::Bytes([[0x11; 20], [0x22; 20]].concat());
+        let records: Vec<&[u8]> = field.chunks(20).unwrap().collect();
+        assert_eq!(records, vec![&[0x11; 20][..], &[0x22; 20][..]]);
+        assert_eq!(field.chunks(40).unwrap().count(), 1);
+        assert!(field.chunks(26).is_none());
+        assert!(field.chunks(0).is_none());
+        assert!(BencodeObject::Bytes(vec![]).chunks(20).unwrap().next().is_none());
+        assert!(bencode!["a"].chunks(1).is_none());
+    }
+
+    #[test]
+    fn test_write_to_path() {
+        let dir = env::temp_dir().join(format!("bencode-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let obj = bencode![1, "two", bencode!["three"]];
+
+        let path = dir.join("plain.bencode");
+        obj.write_to_path(&path).unwrap();
+        assert_eq!(BencodeObject::parse(file_to_bytes(&path).unwrap()).unwrap(), obj);
+
+        let path = dir.join("atomic.bencode");
+        obj.write_to_path_atomic(&path).unwrap();
+        assert_eq!(BencodeObject::parse(file_to_bytes(&path).unwrap()).unwrap(), obj);
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, vec!["atomic.bencode", "plain.bencode"]);
+
+        // A failed write leaves no temp file behind.
+        assert!(obj.write_to_path_atomic(&dir.join("missing").join("x.bencode")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_length_exceeds_input() {
+        match BencodeObject::parse("10:asdf") {
+            Err(BencodeError::UnexpectedEndOfInput) => (),
+            other => panic!("expected UnexpectedEndOfInput, got {:?}", other),
+        }
+    }
+}
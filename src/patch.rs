@@ -0,0 +1,185 @@
+// RFC 6902 (JSON Patch) style edits over BencodeObject. Paths are JSON Pointers: `/`-separated
+// components with `~1` for `/` and `~0` for `~`, where the empty path is the whole document.
+// List components are indices, and `-` appends when adding.
+
+use std::mem;
+
+use BencodeObject;
+use err::BencodeError;
+
+
+#[derive(Clone, Debug)]
+pub enum BencodePatchOp {
+    Add { path: String, value: BencodeObject },
+    Remove { path: String },
+    Replace { path: String, value: BencodeObject },
+    Copy { from: String, path: String },
+    Move { from: String, path: String },
+}
+
+impl BencodeObject {
+    // Applies `ops` in order. Either every op succeeds or `self` is left untouched.
+    pub fn apply_patch(&mut self, ops: &[BencodePatchOp]) -> Result<(), BencodeError> {
+        let mut patched = self.clone();
+        for op in ops {
+            match *op {
+                BencodePatchOp::Add { ref path, ref value } => {
+                    add(&mut patched, &parse_pointer(path)?, value.clone(), path)?;
+                },
+                BencodePatchOp::Remove { ref path } => {
+                    remove(&mut patched, &parse_pointer(path)?, path)?;
+                },
+                BencodePatchOp::Replace { ref path, ref value } => {
+                    let target = lookup_mut(&mut patched, &parse_pointer(path)?)
+                        .ok_or_else(|| BencodeError::InvalidPatchPath(path.clone()))?;
+                    *target = value.clone();
+                },
+                BencodePatchOp::Copy { ref from, ref path } => {
+                    let value = lookup_mut(&mut patched, &parse_pointer(from)?)
+                        .ok_or_else(|| BencodeError::InvalidPatchPath(from.clone()))?
+                        .clone();
+                    add(&mut patched, &parse_pointer(path)?, value, path)?;
+                },
+                BencodePatchOp::Move { ref from, ref path } => {
+                    let from_tokens = parse_pointer(from)?;
+                    let path_tokens = parse_pointer(path)?;
+                    if path_tokens.len() > from_tokens.len() && path_tokens[..from_tokens.len()] == from_tokens[..] {
+                        return Err(BencodeError::InvalidPatchPath(path.clone()));
+                    }
+                    let value = remove(&mut patched, &from_tokens, from)?;
+                    add(&mut patched, &path_tokens, value, path)?;
+                },
+            }
+        }
+        *self = patched;
+        Ok(())
+    }
+}
+
+fn parse_pointer(path: &str) -> Result<Vec<String>, BencodeError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !path.starts_with('/') {
+        return Err(BencodeError::InvalidPatchPath(path.to_string()));
+    }
+    Ok(path[1..].split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn list_index(token: &str, len: usize) -> Option<usize> {
+    if token.len() > 1 && token.starts_with('0') {
+        return None;
+    }
+    token.parse::<usize>().ok().filter(|&index| index < len)
+}
+
+fn lookup_mut<'a>(obj: &'a mut BencodeObject, tokens: &[String]) -> Option<&'a mut BencodeObject> {
+    let mut current = obj;
+    for token in tokens {
+        current = match *current {
+            BencodeObject::Dict(ref mut dict) => dict.get_mut(token)?,
+            BencodeObject::List(ref mut list) => {
+                let index = list_index(token, list.len())?;
+                &mut list[index]
+            },
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn add(obj: &mut BencodeObject, tokens: &[String], value: BencodeObject, path: &str) -> Result<(), BencodeError> {
+    let invalid = || BencodeError::InvalidPatchPath(path.to_string());
+    let (last, parent_tokens) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *obj = value;
+            return Ok(());
+        },
+    };
+    match *lookup_mut(obj, parent_tokens).ok_or_else(invalid)? {
+        BencodeObject::Dict(ref mut dict) => {
+            dict.insert(last.clone(), value);
+        },
+        BencodeObject::List(ref mut list) => {
+            if last == "-" {
+                list.push(value);
+            } else {
+                let index = list_index(last, list.len() + 1).ok_or_else(invalid)?;
+                list.insert(index, value);
+            }
+        },
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+fn remove(obj: &mut BencodeObject, tokens: &[String], path: &str) -> Result<BencodeObject, BencodeError> {
+    let invalid = || BencodeError::InvalidPatchPath(path.to_string());
+    let (last, parent_tokens) = match tokens.split_last() {
+        Some(split) => split,
+        None => return Ok(mem::replace(obj, BencodeObject::Dict(Default::default()))),
+    };
+    match *lookup_mut(obj, parent_tokens).ok_or_else(invalid)? {
+        BencodeObject::Dict(ref mut dict) => dict.remove(last).ok_or_else(invalid),
+        BencodeObject::List(ref mut list) => {
+            let index = list_index(last, list.len()).ok_or_else(invalid)?;
+            Ok(list.remove(index))
+        },
+        _ => Err(invalid()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use BencodeObject;
+    use err::BencodeError;
+    use patch::BencodePatchOp;
+
+    fn torrent() -> BencodeObject {
+        BencodeObject::parse("d8:announce3:foo4:infod5:filesl1:a1:be6:lengthi5e4:name3:baree").unwrap()
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut obj = torrent();
+        obj.apply_patch(&[
+            BencodePatchOp::Add { path: "/info/private".to_string(), value: BencodeObject::Integer(1) },
+            BencodePatchOp::Remove { path: "/announce".to_string() },
+            BencodePatchOp::Replace { path: "/info/name".to_string(), value: BencodeObject::Bytes(b"baz".to_vec()) },
+            BencodePatchOp::Add { path: "/info/files/1".to_string(), value: BencodeObject::Bytes(b"c".to_vec()) },
+            BencodePatchOp::Add { path: "/info/files/-".to_string(), value: BencodeObject::Bytes(b"d".to_vec()) },
+        ]).unwrap();
+
+        assert_eq!(format!("{:?}", obj), format!("{:?}",
+            BencodeObject::parse("d4:infod5:filesl1:a1:c1:b1:de6:lengthi5e4:name3:baz7:privatei1eee").unwrap()));
+    }
+
+    #[test]
+    fn test_apply_patch_copy_move() {
+        let mut obj = torrent();
+        obj.apply_patch(&[
+            BencodePatchOp::Copy { from: "/info/name".to_string(), path: "/title".to_string() },
+            BencodePatchOp::Move { from: "/info/files/0".to_string(), path: "/first~1file".to_string() },
+        ]).unwrap();
+
+        assert_eq!(format!("{:?}", obj), format!("{:?}",
+            BencodeObject::parse("d8:announce3:foo10:first/file1:a4:infod5:filesl1:be6:lengthi5e4:name3:bare5:title3:bare").unwrap()));
+    }
+
+    #[test]
+    fn test_apply_patch_is_atomic() {
+        let mut obj = torrent();
+        let before = format!("{:?}", obj);
+        let result = obj.apply_patch(&[
+            BencodePatchOp::Remove { path: "/announce".to_string() },
+            BencodePatchOp::Remove { path: "/info/missing".to_string() },
+        ]);
+        match result {
+            Err(BencodeError::InvalidPatchPath(ref path)) => assert_eq!(path, "/info/missing"),
+            other => panic!("expected InvalidPatchPath, got {:?}", other),
+        }
+        assert_eq!(format!("{:?}", obj), before);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::time::{Duration, Instant};
+
+
+// Expiring set of recently seen keys, e.g. (NodeId, query) pairs, for rate limiting and
+// deduplication. Keys live in two generations: once the current one reaches `capacity` it
+// becomes the previous one and the old previous one is dropped, so at most 2 * capacity
+// keys are held no matter how many distinct keys are inserted.
+pub struct RecentSet<K: Hash + Eq> {
+    capacity: usize,
+    current: HashMap<K, Instant>,
+    previous: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq> RecentSet<K> {
+    pub fn new(capacity: usize) -> RecentSet<K> {
+        RecentSet {
+            capacity,
+            current: HashMap::new(),
+            previous: HashMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, key: K, now: Instant) {
+        if self.current.len() >= self.capacity && !self.current.contains_key(&key) {
+            self.previous = mem::take(&mut self.current);
+        }
+        self.current.insert(key, now);
+    }
+
+    // True if `key` was inserted less than `window` before `now`.
+    pub fn contains_fresh(&self, key: &K, now: Instant, window: Duration) -> bool {
+        self.current.get(key).or_else(|| self.previous.get(key))
+            .is_some_and(|&seen| now.saturating_duration_since(seen) < window)
+    }
+
+    pub fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use kademlia::NodeId;
+    use std::time::{Duration, Instant};
+    use recent::RecentSet;
+
+    fn id(n: u32) -> NodeId {
+        let mut bytes = [0; 20];
+        bytes[16..].copy_from_slice(&n.to_be_bytes());
+        NodeId::from_bytes(&bytes)
+    }
+
+    #[test]
+    fn test_recent_set_expires() {
+        let id = id(12345);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let window = Duration::from_secs(10);
+        let mut recent = RecentSet::new(16);
+        recent.insert((id, "get_peers"), at(100));
+        assert!(recent.contains_fresh(&(id, "get_peers"), at(100), window));
+        assert!(recent.contains_fresh(&(id, "get_peers"), at(109), window));
+        assert!(!recent.contains_fresh(&(id, "get_peers"), at(110), window));
+        assert!(!recent.contains_fresh(&(id, "find_node"), at(100), window));
+
+        // Re-inserting refreshes the timestamp.
+        recent.insert((id, "get_peers"), at(120));
+        assert!(recent.contains_fresh(&(id, "get_peers"), at(125), window));
+    }
+
+    #[test]
+    fn test_recent_set_bounded() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let window = Duration::from_secs(10);
+        let mut recent = RecentSet::new(100);
+        for i in 0..10000u32 {
+            recent.insert((id(i), "ping"), at(i as u64));
+            assert!(recent.len() <= 200);
+        }
+        assert!(recent.contains_fresh(&(id(9999), "ping"), at(10000), window));
+        assert!(recent.contains_fresh(&(id(9950), "ping"), at(9955), window));
+        assert!(!recent.contains_fresh(&(id(0), "ping"), at(0), window));
+    }
+}
@@ -0,0 +1,560 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::mem;
+use std::str::from_utf8;
+
+use {BencodeObject, _parse_int};
+use err::BencodeError;
+
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+// Integers and length prefixes longer than this can't be valid (an i64 or usize has at most
+// 20 digits), so they are rejected before the whole run is buffered.
+const MAX_NUMBER_LEN: usize = 24;
+
+// parse_value recurses once per nested list or dict, so nesting is capped well below what
+// the smallest thread stacks can hold.
+const MAX_DEPTH: usize = 512;
+
+// Incremental parser over a reader. Input is pulled BUFFER_SIZE bytes at a time, and byte
+// strings are filled in as data arrives rather than allocated up front from their declared
+// length, so a lying length prefix on a short stream fails with UnexpectedEndOfInput instead
+// of a huge allocation. Error positions are offsets into the stream.
+pub struct StreamParser<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    // Stream offset of buffer[start].
+    position: usize,
+    limit: Option<usize>,
+    // Lists and dicts currently open.
+    depth: usize,
+}
+
+impl<R: Read> StreamParser<R> {
+    pub fn new(reader: R, limit: Option<usize>) -> StreamParser<R> {
+        StreamParser {
+            reader,
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            position: 0,
+            limit,
+            depth: 0
+        }
+    }
+
+    // Parses exactly one value and checks that the stream ends right after it.
+    pub fn parse_all(&mut self) -> Result<BencodeObject, BencodeError> {
+        let value = self.parse_value()?;
+        if self.fill()? {
+            Err(BencodeError::UnexpectedCharacter(self.position))
+        } else {
+            Ok(value)
+        }
+    }
+
+    // Makes at least one unread byte available, returning false at the end of the stream.
+    fn fill(&mut self) -> Result<bool, BencodeError> {
+        while self.start == self.end {
+            match self.reader.read(&mut self.buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.start = 0;
+                    self.end = n;
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(BencodeError::Io(err)),
+            }
+        }
+        Ok(true)
+    }
+
+    fn peek(&mut self) -> Result<u8, BencodeError> {
+        if self.fill()? {
+            Ok(self.buffer[self.start])
+        } else {
+            Err(BencodeError::UnexpectedEndOfInput)
+        }
+    }
+
+    fn advance(&mut self, n: usize) -> Result<(), BencodeError> {
+        self.start += n;
+        self.position += n;
+        match self.limit {
+            Some(limit) if self.position > limit => Err(BencodeError::InputTooLarge(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    // Reads bytes up to and including `terminator`, failing at the first byte that isn't
+    // allowed in a number.
+    fn read_number(&mut self, terminator: u8) -> Result<Vec<u8>, BencodeError> {
+        let start = self.position;
+        let mut token = Vec::new();
+        loop {
+            let b = self.peek()?;
+            token.push(b);
+            self.advance(1)?;
+            if b == terminator {
+                return Ok(token);
+            }
+            if !(b.is_ascii_digit() || b == b'-' || b == b'i') || token.len() > MAX_NUMBER_LEN {
+                return Err(BencodeError::UnexpectedCharacter(start + token.len() - 1));
+            }
+        }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, BencodeError> {
+        if let Some(limit) = self.limit {
+            if len > limit.saturating_sub(self.position) {
+                return Err(BencodeError::InputTooLarge(limit));
+            }
+        }
+        let mut bytes = Vec::with_capacity(len.min(BUFFER_SIZE));
+        while bytes.len() < len {
+            if !self.fill()? {
+                return Err(BencodeError::UnexpectedEndOfInput);
+            }
+            let n = (len - bytes.len()).min(self.end - self.start);
+            bytes.extend_from_slice(&self.buffer[self.start .. self.start + n]);
+            self.advance(n)?;
+        }
+        Ok(bytes)
+    }
+
+    // Consumes the `l` or `d` at `start`.
+    fn enter(&mut self, start: usize) -> Result<(), BencodeError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(BencodeError::TooDeep(start));
+        }
+        self.depth += 1;
+        self.advance(1)
+    }
+
+    // Consumes the closing `e`.
+    fn leave(&mut self) -> Result<(), BencodeError> {
+        self.depth -= 1;
+        self.advance(1)
+    }
+
+    fn parse_value(&mut self) -> Result<BencodeObject, BencodeError> {
+        let start = self.position;
+        match self.peek()? {
+            b'i' => {
+                let token = self.read_number(b'e')?;
+                _parse_int(&token, &mut 0).map(BencodeObject::Integer).map_err(|err| offset_by(err, start))
+            },
+            b'0' ..= b'9' => {
+                let token = self.read_number(b':')?;
+                let len = parse_length(&token[.. token.len() - 1], start)?;
+                Ok(BencodeObject::Bytes(self.read_bytes(len)?))
+            },
+            b'l' => {
+                self.enter(start)?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.parse_value()?);
+                }
+                self.leave()?;
+                Ok(BencodeObject::List(list))
+            },
+            b'd' => {
+                self.enter(start)?;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key_start = self.position;
+                    let key = match self.parse_value()? {
+                        BencodeObject::Bytes(key) => String::from_utf8(key).map_err(|err| BencodeError::Utf8(err.utf8_error()))?,
+                        _ => return Err(BencodeError::DictionaryKeyNotString),
+                    };
+                    if dict.keys().next_back().is_some_and(|last| key <= *last) {
+                        return Err(BencodeError::UnorderedDictKeys(key_start));
+                    }
+                    let value = self.parse_value()?;
+                    dict.insert(key, value);
+                }
+                self.leave()?;
+                Ok(BencodeObject::Dict(dict))
+            },
+            _ => Err(BencodeError::UnexpectedCharacter(start)),
+        }
+    }
+}
+
+enum Frame {
+    List(Vec<BencodeObject>),
+    // Entries so far, and the key still waiting for its value.
+    Dict(BTreeMap<String, BencodeObject>, Option<String>),
+}
+
+// The scalar being read when a chunk ran out, with the stream offset it started at.
+enum Partial {
+    None,
+    Integer(Vec<u8>, usize),
+    Length(Vec<u8>, usize),
+    // Bytes read so far and how many are still to come.
+    Bytes(Vec<u8>, usize, usize),
+}
+
+// Push parser for input that arrives in pieces, e.g. off a socket. Each `feed` continues where
+// the last one stopped and returns the next complete top-level value, if there is one yet.
+// Input after that value is kept for the following call, so back-to-back values come out one
+// per call; `feed(&[])` drains them. Containers are tracked on an explicit stack rather than by
+// recursion. Error positions count from the first byte ever fed, and the parser shouldn't be
+// reused after an error.
+pub struct BencodeParser {
+    stack: Vec<Frame>,
+    partial: Partial,
+    // Fed but not yet consumed, because a value completed before it.
+    pending: Vec<u8>,
+    // Stream offset of pending[0].
+    position: usize,
+}
+
+impl BencodeParser {
+    pub fn new() -> BencodeParser {
+        BencodeParser {
+            stack: Vec::new(),
+            partial: Partial::None,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Option<BencodeObject>, BencodeError> {
+        let mut input = mem::take(&mut self.pending);
+        input.extend_from_slice(bytes);
+        let mut i = 0;
+        let result = self.consume(&input, &mut i);
+        self.pending = input.split_off(i);
+        self.position += i;
+        result
+    }
+
+    // Whether a value has been started but not finished, i.e. ending the stream here would
+    // truncate it.
+    pub fn is_partial(&self) -> bool {
+        !self.stack.is_empty() || !matches!(self.partial, Partial::None)
+    }
+
+    fn consume(&mut self, input: &[u8], i: &mut usize) -> Result<Option<BencodeObject>, BencodeError> {
+        while *i < input.len() {
+            let at = self.position + *i;
+            let b = input[*i];
+            let complete = match mem::replace(&mut self.partial, Partial::None) {
+                Partial::Bytes(mut bytes, remaining, start) => {
+                    let n = remaining.min(input.len() - *i);
+                    bytes.extend_from_slice(&input[*i .. *i + n]);
+                    *i += n;
+                    if n < remaining {
+                        self.partial = Partial::Bytes(bytes, remaining - n, start);
+                        continue;
+                    }
+                    Some((BencodeObject::Bytes(bytes), start))
+                },
+                Partial::Integer(mut token, start) => {
+                    *i += 1;
+                    token.push(b);
+                    if b == b'e' {
+                        let n = _parse_int(&token, &mut 0).map_err(|err| offset_by(err, start))?;
+                        Some((BencodeObject::Integer(n), start))
+                    } else if (b.is_ascii_digit() || b == b'-') && token.len() <= MAX_NUMBER_LEN {
+                        self.partial = Partial::Integer(token, start);
+                        None
+                    } else {
+                        return Err(BencodeError::UnexpectedCharacter(at));
+                    }
+                },
+                Partial::Length(mut digits, start) => {
+                    *i += 1;
+                    if b == b':' {
+                        match parse_length(&digits, start)? {
+                            0 => Some((BencodeObject::Bytes(Vec::new()), start)),
+                            len => {
+                                self.partial = Partial::Bytes(Vec::with_capacity(len.min(BUFFER_SIZE)), len, start);
+                                None
+                            },
+                        }
+                    } else if b.is_ascii_digit() && digits.len() < MAX_NUMBER_LEN {
+                        digits.push(b);
+                        self.partial = Partial::Length(digits, start);
+                        None
+                    } else {
+                        return Err(BencodeError::UnexpectedCharacter(at));
+                    }
+                },
+                Partial::None => {
+                    let expecting_key = matches!(self.stack.last(), Some(&Frame::Dict(_, None)));
+                    *i += 1;
+                    match b {
+                        b'0' ..= b'9' => {
+                            self.partial = Partial::Length(vec![b], at);
+                            None
+                        },
+                        b'e' => match self.stack.pop() {
+                            Some(Frame::List(list)) => Some((BencodeObject::List(list), at)),
+                            Some(Frame::Dict(dict, None)) => Some((BencodeObject::Dict(dict), at)),
+                            _ => return Err(BencodeError::UnexpectedCharacter(at)),
+                        },
+                        b'i' | b'l' | b'd' if expecting_key => return Err(BencodeError::DictionaryKeyNotString),
+                        b'i' => {
+                            self.partial = Partial::Integer(vec![b], at);
+                            None
+                        },
+                        b'l' => {
+                            self.stack.push(Frame::List(Vec::new()));
+                            None
+                        },
+                        b'd' => {
+                            self.stack.push(Frame::Dict(BTreeMap::new(), None));
+                            None
+                        },
+                        _ => return Err(BencodeError::UnexpectedCharacter(at)),
+                    }
+                },
+            };
+            if let Some((value, start)) = complete {
+                if let Some(value) = self.add_to_parent(value, start)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Files a finished value under the innermost open container, or hands it back if it is
+    // the top-level value.
+    fn add_to_parent(&mut self, value: BencodeObject, start: usize) -> Result<Option<BencodeObject>, BencodeError> {
+        match self.stack.last_mut() {
+            None => return Ok(Some(value)),
+            Some(&mut Frame::List(ref mut list)) => list.push(value),
+            Some(&mut Frame::Dict(ref mut dict, ref mut pending_key)) => match pending_key.take() {
+                Some(key) => {
+                    dict.insert(key, value);
+                },
+                None => {
+                    let key = match value {
+                        BencodeObject::Bytes(key) => String::from_utf8(key).map_err(|err| BencodeError::Utf8(err.utf8_error()))?,
+                        _ => return Err(BencodeError::DictionaryKeyNotString),
+                    };
+                    if dict.keys().next_back().is_some_and(|last| key <= *last) {
+                        return Err(BencodeError::UnorderedDictKeys(start));
+                    }
+                    *pending_key = Some(key);
+                },
+            },
+        }
+        Ok(None)
+    }
+}
+
+// Decodes a string length prefix, without its colon, that starts at stream offset `start`.
+fn parse_length(digits: &[u8], start: usize) -> Result<usize, BencodeError> {
+    if let Some(bad) = digits.iter().position(|b| !b.is_ascii_digit()) {
+        return Err(BencodeError::UnexpectedCharacter(start + bad));
+    }
+    let len = from_utf8(digits)?.parse::<usize>()?;
+    if len > isize::MAX as usize {
+        return Err(BencodeError::LengthOverflow(start));
+    }
+    Ok(len)
+}
+
+// Moves a position reported against a token buffer to the token's place in the stream.
+fn offset_by(err: BencodeError, offset: usize) -> BencodeError {
+    match err {
+        BencodeError::UnexpectedCharacter(position) => BencodeError::UnexpectedCharacter(position + offset),
+        BencodeError::LengthOverflow(position) => BencodeError::LengthOverflow(position + offset),
+        err => err,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Read};
+    use BencodeObject;
+    use err::BencodeError;
+    use stream::{BencodeParser, MAX_DEPTH};
+
+    // Hands out at most `chunk` bytes per read, to force refills mid-token.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = cmp::min(cmp::min(self.chunk, buf.len()), self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+        }
+    }
+
+    #[test]
+    fn test_from_reader_matches_parse() {
+        let mut input = b"d4:infod6:lengthi-42e4:name3:foo6:pieces20000:".to_vec();
+        input.extend(vec![b'x'; 20000]);
+        input.extend(b"e4:listli1ei22e333:".iter());
+        input.extend(vec![b'y'; 333]);
+        input.extend(b"ee".iter());
+        let expected = BencodeObject::parse(&input[..]).unwrap();
+
+        for &chunk in [1, 3, 7, 4096, 100000].iter() {
+            let parsed = BencodeObject::from_reader(Trickle { bytes: &input, chunk }).unwrap();
+            assert_eq!(parsed, expected, "chunk size {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_from_reader_errors() {
+        match BencodeObject::from_reader(Trickle { bytes: b"l5:ab", chunk: 2 }) {
+            Err(BencodeError::UnexpectedEndOfInput) => (),
+            other => panic!("expected UnexpectedEndOfInput, got {:?}", other),
+        }
+        match BencodeObject::from_reader(Trickle { bytes: b"99999999999:abc", chunk: 4 }) {
+            Err(BencodeError::UnexpectedEndOfInput) => (),
+            other => panic!("expected UnexpectedEndOfInput, got {:?}", other),
+        }
+        match BencodeObject::from_reader(Trickle { bytes: b"li1ei01ee", chunk: 2 }) {
+            Err(BencodeError::UnexpectedCharacter(5)) => (),
+            other => panic!("expected UnexpectedCharacter(5), got {:?}", other),
+        }
+        match BencodeObject::from_reader(Trickle { bytes: b"i1ei2e", chunk: 2 }) {
+            Err(BencodeError::UnexpectedCharacter(3)) => (),
+            other => panic!("expected UnexpectedCharacter(3), got {:?}", other),
+        }
+        match BencodeObject::from_reader(Trickle { bytes: b"d1:b1:x1:a1:ye", chunk: 2 }) {
+            Err(BencodeError::UnorderedDictKeys(7)) => (),
+            other => panic!("expected UnorderedDictKeys(7), got {:?}", other),
+        }
+        match BencodeObject::from_reader(Failing) {
+            Err(BencodeError::Io(ref err)) if err.kind() == io::ErrorKind::ConnectionReset => (),
+            other => panic!("expected Io, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_from_reader_with_limit() {
+        let input = b"l4:spami42ee";
+        assert!(BencodeObject::from_reader_with_limit(Trickle { bytes: input, chunk: 3 }, input.len()).is_ok());
+        match BencodeObject::from_reader_with_limit(Trickle { bytes: input, chunk: 3 }, input.len() - 1) {
+            Err(BencodeError::InputTooLarge(11)) => (),
+            other => panic!("expected InputTooLarge, got {:?}", other),
+        }
+        // The declared length alone is enough to refuse.
+        match BencodeObject::from_reader_with_limit(Trickle { bytes: b"1000000:abc", chunk: 3 }, 1024) {
+            Err(BencodeError::InputTooLarge(1024)) => (),
+            other => panic!("expected InputTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_from_reader_depth_limit() {
+        let mut nested = vec![b'l'; MAX_DEPTH];
+        nested.extend(vec![b'e'; MAX_DEPTH]);
+        assert!(BencodeObject::from_reader(Trickle { bytes: &nested, chunk: 64 }).is_ok());
+
+        // Without the limit this would recurse a million frames deep before finding out.
+        match BencodeObject::from_reader(Trickle { bytes: &vec![b'l'; 1000000], chunk: 4096 }) {
+            Err(BencodeError::TooDeep(position)) => assert_eq!(position, MAX_DEPTH),
+            other => panic!("expected TooDeep, got {:?}", other),
+        }
+    }
+
+    // Feeds `input` in `chunk`-sized pieces, collecting every value that comes out.
+    fn feed_all(input: &[u8], chunk: usize) -> Result<Vec<BencodeObject>, BencodeError> {
+        let mut parser = BencodeParser::new();
+        let mut values = Vec::new();
+        for piece in input.chunks(chunk) {
+            if let Some(value) = parser.feed(piece)? {
+                values.push(value);
+            }
+            while let Some(value) = parser.feed(&[])? {
+                values.push(value);
+            }
+        }
+        assert!(!parser.is_partial());
+        Ok(values)
+    }
+
+    #[test]
+    fn test_push_parser_chunked() {
+        let input = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let expected = BencodeObject::parse(&input[..]).unwrap();
+        for chunk in 1..input.len() + 1 {
+            assert_eq!(feed_all(input, chunk).unwrap(), vec![expected.clone()], "chunk size {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_push_parser_back_to_back() {
+        let values = feed_all(b"i1e0:li-2eed1:xlee4:spam", 3).unwrap();
+        assert_eq!(values, vec![
+            BencodeObject::Integer(1),
+            BencodeObject::Bytes(vec![]),
+            BencodeObject::List(vec![BencodeObject::Integer(-2)]),
+            BencodeObject::parse("d1:xlee").unwrap(),
+            BencodeObject::Bytes(b"spam".to_vec()),
+        ]);
+
+        let mut parser = BencodeParser::new();
+        assert_eq!(parser.feed(b"l4:sp").unwrap(), None);
+        assert!(parser.is_partial());
+        assert_eq!(parser.feed(b"ame").unwrap(), Some(BencodeObject::List(vec![BencodeObject::Bytes(b"spam".to_vec())])));
+        assert!(!parser.is_partial());
+    }
+
+    #[test]
+    fn test_push_parser_errors() {
+        let mut parser = BencodeParser::new();
+        assert_eq!(parser.feed(b"i1ei").unwrap(), Some(BencodeObject::Integer(1)));
+        match parser.feed(b"01e") {
+            Err(BencodeError::UnexpectedCharacter(4)) => (),
+            other => panic!("expected UnexpectedCharacter(4), got {:?}", other),
+        }
+
+        match feed_all(b"d1:b1:x1:a1:ye", 2) {
+            Err(BencodeError::UnorderedDictKeys(7)) => (),
+            other => panic!("expected UnorderedDictKeys(7), got {:?}", other),
+        }
+        match feed_all(b"di1ei2ee", 2) {
+            Err(BencodeError::DictionaryKeyNotString) => (),
+            other => panic!("expected DictionaryKeyNotString, got {:?}", other),
+        }
+        match feed_all(b"le4:spam3x", 4) {
+            Err(BencodeError::UnexpectedCharacter(9)) => (),
+            other => panic!("expected UnexpectedCharacter(9), got {:?}", other),
+        }
+        match feed_all(b"d1:ae", 1) {
+            Err(BencodeError::UnexpectedCharacter(4)) => (),
+            other => panic!("expected UnexpectedCharacter(4), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_integers_match_parse() {
+        for input in ["i03e", "i-0e", "ie", "li1ei-03ee"].iter() {
+            let expected = format!("{:?}", BencodeObject::parse(*input));
+            assert!(expected.starts_with("Err(UnexpectedCharacter("), "{}", expected);
+            let streamed = BencodeObject::from_reader(Trickle { bytes: input.as_bytes(), chunk: 1 });
+            assert_eq!(format!("{:?}", streamed), expected, "from_reader on {}", input);
+            assert_eq!(format!("{:?}", feed_all(input.as_bytes(), 1).map(|mut values| values.remove(0))), expected, "feed on {}", input);
+        }
+        assert_eq!(BencodeObject::from_reader(Trickle { bytes: b"i0e", chunk: 1 }).unwrap(), BencodeObject::Integer(0));
+        assert_eq!(feed_all(b"i0e", 1).unwrap(), vec![BencodeObject::Integer(0)]);
+    }
+}
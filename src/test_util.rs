@@ -0,0 +1,95 @@
+// Helpers for golden tests against recorded bencode, and for randomized tests.
+
+use BencodeObject;
+
+
+// Panics with a path-by-path structural diff if `obj` differs from the parse of `bytes`.
+pub fn assert_eq_bytes(obj: &BencodeObject, bytes: &[u8]) {
+    let expected = match BencodeObject::parse(bytes) {
+        Ok(expected) => expected,
+        Err(err) => panic!("expected bytes are not valid bencode: {}", err),
+    };
+    let mut differences = Vec::new();
+    diff(obj, &expected, "", &mut differences);
+    if !differences.is_empty() {
+        panic!("bencode mismatch:\n{}", differences.join("\n"));
+    }
+}
+
+// Collects one line per differing path, in JSON Pointer style (`""` is the root).
+pub fn diff(actual: &BencodeObject, expected: &BencodeObject, path: &str, differences: &mut Vec<String>) {
+    match (actual, expected) {
+        (BencodeObject::List(actual), BencodeObject::List(expected)) => {
+            for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+                diff(a, e, &format!("{}/{}", path, i), differences);
+            }
+            if actual.len() != expected.len() {
+                differences.push(format!("{}: expected {} items, got {}", path, expected.len(), actual.len()));
+            }
+        },
+        (BencodeObject::Dict(actual), BencodeObject::Dict(expected)) => {
+            for (key, e) in expected {
+                let key_path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                match actual.get(key) {
+                    Some(a) => diff(a, e, &key_path, differences),
+                    None => differences.push(format!("{}: missing, expected {}", key_path, e.summary())),
+                }
+            }
+            for (key, a) in actual {
+                if !expected.contains_key(key) {
+                    let key_path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                    differences.push(format!("{}: unexpected {}", key_path, a.summary()));
+                }
+            }
+        },
+        (actual, expected) => {
+            if actual != expected {
+                differences.push(format!("{}: expected {}, got {}", path, expected.summary(), actual.summary()));
+            }
+        },
+    }
+}
+
+// Deterministic xorshift stream standing in for a property-test generator.
+pub fn random_values(seed: u64) -> impl Iterator<Item = u64> {
+    let mut state = seed;
+    (0..).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::panic;
+    use {BencodeObject, Bencodeable};
+    use test_util::assert_eq_bytes;
+
+    #[test]
+    fn test_assert_eq_bytes_matches() {
+        assert_eq_bytes(&bencode!["a" => 1, "b" => bencode!["x", "y"]], b"d1:ai1e1:bl1:x1:yee");
+    }
+
+    #[test]
+    fn test_assert_eq_bytes_reports_diff() {
+        let result = panic::catch_unwind(|| {
+            let obj = bencode![
+                "info" => bencode!["name" => "foo", "length" => 5],
+                "list" => bencode![1, 2, 3],
+                "extra" => 1
+            ];
+            assert_eq_bytes(&obj, b"d4:infod6:lengthi6e4:name3:foo7:privatei1ee4:listli1ei2eee");
+        });
+        let err = result.unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert_eq!(message, "bencode mismatch:\n\
+/info/length: expected 6, got 5\n\
+/info/private: missing, expected 1\n\
+/list: expected 2 items, got 3\n\
+/extra: unexpected 1");
+    }
+}
@@ -0,0 +1,205 @@
+use err::BencodeError;
+use {_parse_int, _parse_bytes};
+
+
+#[derive(Debug, PartialEq)]
+pub enum Token<'a> {
+    Integer(i64),
+    Bytes(&'a [u8]),
+    ListStart,
+    ListEnd,
+    DictStart,
+    DictEnd,
+}
+
+// Pull-based scanner over a single bencoded value. It yields tokens in input order without
+// building the object tree; byte strings are borrowed straight from the input. Only the
+// container nesting is tracked, so dict keys are checked to be byte strings and ends are
+// checked to match, but keys are not UTF-8 validated.
+//
+// Scanning stops after the first complete top-level value. Compare `position()` against the
+// input length to detect trailing bytes.
+pub struct Tokenizer<'a> {
+    bytes: &'a [u8],
+    i: usize,
+    // One entry per open container: None for a list, Some(expecting_key) for a dict.
+    stack: Vec<Option<bool>>,
+    done: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(bytes: &'a [u8]) -> Tokenizer<'a> {
+        Tokenizer {
+            bytes,
+            i: 0,
+            stack: Vec::new(),
+            done: false
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.i
+    }
+
+    // Consumes the next complete value, including everything nested in it, and returns its
+    // raw bytes.
+    pub fn skip_value(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.i;
+        let depth = self.stack.len();
+        loop {
+            match self.next() {
+                Some(Ok(Token::ListEnd)) | Some(Ok(Token::DictEnd)) if self.stack.len() < depth => {
+                    return Err(BencodeError::UnexpectedCharacter(self.i - 1));
+                },
+                Some(Ok(_)) => {
+                    if self.stack.len() == depth {
+                        return Ok(&self.bytes[start .. self.i]);
+                    }
+                },
+                Some(Err(err)) => return Err(err),
+                None => return Err(BencodeError::UnexpectedEndOfInput),
+            }
+        }
+    }
+
+    fn fail(&mut self, err: BencodeError) -> Option<Result<Token<'a>, BencodeError>> {
+        self.done = true;
+        Some(Err(err))
+    }
+
+    fn value_done(&mut self) {
+        match self.stack.last_mut() {
+            Some(&mut Some(ref mut expecting_key)) => *expecting_key = !*expecting_key,
+            Some(&mut None) => (),
+            None => self.done = true,
+        }
+    }
+
+    fn expecting_key(&self) -> bool {
+        match self.stack.last() {
+            Some(&Some(expecting_key)) => expecting_key,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token<'a>, BencodeError>;
+
+    fn next(&mut self) -> Option<Result<Token<'a>, BencodeError>> {
+        if self.done {
+            return None;
+        }
+        if self.i == self.bytes.len() {
+            return self.fail(BencodeError::UnexpectedEndOfInput);
+        }
+
+        match self.bytes[self.i] {
+            b'e' => {
+                let token = match self.stack.last() {
+                    Some(&None) => Token::ListEnd,
+                    Some(&Some(true)) => Token::DictEnd,
+                    _ => return self.fail(BencodeError::UnexpectedCharacter(self.i)),
+                };
+                self.stack.pop();
+                self.i += 1;
+                self.value_done();
+                Some(Ok(token))
+            },
+            b'0' ..= b'9' => {
+                match _parse_bytes(self.bytes, &mut self.i) {
+                    Ok(bytes) => {
+                        self.value_done();
+                        Some(Ok(Token::Bytes(bytes)))
+                    },
+                    Err(err) => self.fail(err),
+                }
+            },
+            _ if self.expecting_key() => self.fail(BencodeError::DictionaryKeyNotString),
+            b'i' => {
+                match _parse_int(self.bytes, &mut self.i) {
+                    Ok(n) => {
+                        self.value_done();
+                        Some(Ok(Token::Integer(n)))
+                    },
+                    Err(err) => self.fail(err),
+                }
+            },
+            b'l' => {
+                self.i += 1;
+                self.stack.push(None);
+                Some(Ok(Token::ListStart))
+            },
+            b'd' => {
+                self.i += 1;
+                self.stack.push(Some(true));
+                Some(Ok(Token::DictStart))
+            },
+            _ => self.fail(BencodeError::UnexpectedCharacter(self.i)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use err::BencodeError;
+    use tokenizer::{Tokenizer, Token};
+
+    #[test]
+    fn test_tokenize_nested() {
+        let bytes = b"d4:listli1ei-2ee4:name3:foo5:emptydee";
+        let tokens: Vec<Token> = Tokenizer::new(bytes).map(|t| t.unwrap()).collect();
+        assert_eq!(tokens, vec![
+            Token::DictStart,
+            Token::Bytes(b"list"),
+            Token::ListStart,
+            Token::Integer(1),
+            Token::Integer(-2),
+            Token::ListEnd,
+            Token::Bytes(b"name"),
+            Token::Bytes(b"foo"),
+            Token::Bytes(b"empty"),
+            Token::DictStart,
+            Token::DictEnd,
+            Token::DictEnd,
+        ]);
+    }
+
+    #[test]
+    fn test_tokenize_stops_after_value() {
+        let mut tokenizer = Tokenizer::new(b"i1ei2e");
+        assert_eq!(tokenizer.next().unwrap().unwrap(), Token::Integer(1));
+        assert!(tokenizer.next().is_none());
+        assert_eq!(tokenizer.position(), 3);
+    }
+
+    #[test]
+    fn test_skip_value() {
+        let bytes = b"d4:infod6:lengthi5ee4:name3:fooe";
+        let mut tokenizer = Tokenizer::new(bytes);
+        assert_eq!(tokenizer.next().unwrap().unwrap(), Token::DictStart);
+        assert_eq!(tokenizer.next().unwrap().unwrap(), Token::Bytes(b"info"));
+        assert_eq!(tokenizer.skip_value().unwrap(), &b"d6:lengthi5ee"[..]);
+        assert_eq!(tokenizer.next().unwrap().unwrap(), Token::Bytes(b"name"));
+        assert_eq!(tokenizer.skip_value().unwrap(), &b"3:foo"[..]);
+        assert_eq!(tokenizer.next().unwrap().unwrap(), Token::DictEnd);
+        assert!(tokenizer.next().is_none());
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        match Tokenizer::new(b"di1ei2ee").nth(1) {
+            Some(Err(BencodeError::DictionaryKeyNotString)) => (),
+            other => panic!("expected DictionaryKeyNotString, got {:?}", other),
+        }
+        match Tokenizer::new(b"d1:ae").nth(2) {
+            Some(Err(BencodeError::UnexpectedCharacter(4))) => (),
+            other => panic!("expected UnexpectedCharacter, got {:?}", other),
+        }
+        match Tokenizer::new(b"li1e").nth(2) {
+            Some(Err(BencodeError::UnexpectedEndOfInput)) => (),
+            other => panic!("expected UnexpectedEndOfInput, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeSet;
+
+use BencodeObject;
+
+
+// Headline numbers for a torrent, read straight off the bencoded metainfo.
+#[derive(Debug, PartialEq)]
+pub struct TorrentStats {
+    pub name: String,
+    pub total_files: usize,
+    pub total_size_bytes: u64,
+    pub piece_count: usize,
+    pub piece_size: u64,
+    pub tracker_count: usize,
+    // Raw Unix timestamp from `creation date`.
+    pub creation_date: Option<i64>,
+    pub has_private_flag: bool,
+    pub encoding: Option<String>,
+}
+
+impl TorrentStats {
+    // Missing or mistyped fields count as absent rather than failing the whole scan.
+    pub fn from_bencode(obj: &BencodeObject) -> TorrentStats {
+        let empty = BencodeObject::Dict(Default::default());
+        let info = obj.get("info").unwrap_or(&empty);
+
+        let (total_files, total_size_bytes) = match info.get("files") {
+            Some(BencodeObject::List(files)) => {
+                // Saturating, since hostile lengths can add up past u64::MAX.
+                let size = files.iter()
+                    .map(|file| non_negative(file.get_int_or("length", 0)))
+                    .fold(0u64, |total, length| total.saturating_add(length));
+                (files.len(), size)
+            },
+            _ => (1, non_negative(info.get_int_or("length", 0))),
+        };
+
+        let mut trackers = BTreeSet::new();
+        if let Some(BencodeObject::Bytes(announce)) = obj.get("announce") {
+            trackers.insert(&announce[..]);
+        }
+        if let Some(BencodeObject::List(tiers)) = obj.get("announce-list") {
+            for tier in tiers {
+                if let BencodeObject::List(ref urls) = *tier {
+                    for url in urls {
+                        if let BencodeObject::Bytes(ref url) = *url {
+                            trackers.insert(&url[..]);
+                        }
+                    }
+                }
+            }
+        }
+
+        TorrentStats {
+            name: info.get_str_or("name", "").to_string(),
+            total_files,
+            total_size_bytes,
+            piece_count: info.get_bytes_or("pieces", b"").len() / 20,
+            piece_size: non_negative(info.get_int_or("piece length", 0)),
+            tracker_count: trackers.len(),
+            creation_date: match obj.get("creation date") {
+                Some(&BencodeObject::Integer(date)) => Some(date),
+                _ => None,
+            },
+            has_private_flag: info.get_int_or("private", 0) == 1,
+            encoding: match obj.get("encoding") {
+                Some(&BencodeObject::Bytes(_)) => Some(obj.get_str_or("encoding", "").to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+const INFO_KEYS: [&str; 5] = ["name", "piece length", "pieces", "length", "files"];
+
+// Keys that well-known clients add to `info` (BEP 27 `private`, BEP 47 attributes, the
+// `.utf-8` variants and the `md5sum` from the original spec).
+const INFO_EXTRA_KEYS: [&str; 6] = ["private", "source", "md5sum", "name.utf-8", "attr", "sha1"];
+
+#[derive(Debug, PartialEq)]
+pub enum Lint {
+    NotADict,
+    MissingKey(&'static str),
+    // Both `length` and `files`, or neither.
+    AmbiguousLayout,
+    // A standard extra key, reported only when extras aren't allowed.
+    ExtraKey(String),
+    UnknownKey(String),
+}
+
+// Flags anything in an info dict beyond the keys the spec defines. This says nothing about
+// whether the values are well-formed.
+pub fn lint_info(info: &BencodeObject, allow_extras: bool) -> Vec<Lint> {
+    let dict = match *info {
+        BencodeObject::Dict(ref dict) => dict,
+        _ => return vec![Lint::NotADict],
+    };
+
+    let mut lints = Vec::new();
+    for &key in &INFO_KEYS[..3] {
+        if !dict.contains_key(key) {
+            lints.push(Lint::MissingKey(key));
+        }
+    }
+    if dict.contains_key("length") == dict.contains_key("files") {
+        lints.push(Lint::AmbiguousLayout);
+    }
+    for key in dict.keys() {
+        if INFO_KEYS.contains(&&key[..]) {
+            continue;
+        }
+        if INFO_EXTRA_KEYS.contains(&&key[..]) {
+            if !allow_extras {
+                lints.push(Lint::ExtraKey(key.clone()));
+            }
+        } else {
+            lints.push(Lint::UnknownKey(key.clone()));
+        }
+    }
+    lints
+}
+
+fn non_negative(n: i64) -> u64 {
+    if n < 0 { 0 } else { n as u64 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use BencodeObject;
+    use Bencodeable;
+    use std::collections::BTreeMap;
+    use torrent::{TorrentStats, Lint, lint_info};
+
+    #[test]
+    fn test_stats_multi_file() {
+        let torrent = bencode![
+            "announce" => "udp://a",
+            "announce-list" => bencode![bencode!["udp://a", "udp://b"], bencode!["udp://c"]],
+            "creation date" => 1476367200,
+            "encoding" => "UTF-8",
+            "info" => bencode![
+                "name" => "album",
+                "piece length" => 262144,
+                "pieces" => vec![0u8; 60],
+                "private" => 1,
+                "files" => bencode![
+                    bencode!["length" => 1000, "path" => bencode!["a.flac"]],
+                    bencode!["length" => 2500, "path" => bencode!["b.flac"]]
+                ]
+            ]
+        ];
+
+        assert_eq!(TorrentStats::from_bencode(&torrent), TorrentStats {
+            name: "album".to_string(),
+            total_files: 2,
+            total_size_bytes: 3500,
+            piece_count: 3,
+            piece_size: 262144,
+            tracker_count: 3,
+            creation_date: Some(1476367200),
+            has_private_flag: true,
+            encoding: Some("UTF-8".to_string()),
+        });
+    }
+
+    #[test]
+    fn test_stats_single_file() {
+        let torrent = bencode![
+            "info" => bencode![
+                "name" => "ubuntu.iso",
+                "length" => 1593835520,
+                "piece length" => 524288,
+                "pieces" => vec![0u8; 20]
+            ]
+        ];
+
+        assert_eq!(TorrentStats::from_bencode(&torrent), TorrentStats {
+            name: "ubuntu.iso".to_string(),
+            total_files: 1,
+            total_size_bytes: 1593835520,
+            piece_count: 1,
+            piece_size: 524288,
+            tracker_count: 0,
+            creation_date: None,
+            has_private_flag: false,
+            encoding: None,
+        });
+    }
+
+    #[test]
+    fn test_stats_total_size_saturates() {
+        let file = || bencode!["length" => i64::MAX, "path" => bencode!["a"]];
+        let torrent = bencode!["info" => bencode!["files" => bencode![file(), file(), file()]]];
+        let stats = TorrentStats::from_bencode(&torrent);
+        assert_eq!(stats.total_files, 3);
+        assert_eq!(stats.total_size_bytes, u64::MAX);
+    }
+
+    #[test]
+    fn test_lint_info() {
+        let info = bencode![
+            "name" => "ubuntu.iso",
+            "length" => 1593835520,
+            "piece length" => 524288,
+            "pieces" => vec![0u8; 20],
+            "private" => 1,
+            "x-tracker-hint" => "abc"
+        ];
+        assert_eq!(lint_info(&info, true), vec![Lint::UnknownKey("x-tracker-hint".to_string())]);
+        assert_eq!(lint_info(&info, false), vec![
+            Lint::ExtraKey("private".to_string()),
+            Lint::UnknownKey("x-tracker-hint".to_string()),
+        ]);
+
+        let info = bencode!["name" => "album", "pieces" => vec![0u8; 20]];
+        assert_eq!(lint_info(&info, true), vec![Lint::MissingKey("piece length"), Lint::AmbiguousLayout]);
+        assert_eq!(lint_info(&bencode!["name"], true), vec![Lint::NotADict]);
+    }
+}